@@ -0,0 +1,40 @@
+setc calls 0
+setc a 1
+setc b 2
+setc c 3
+setc d 4
+setc e 5
+setc f 6
+setc g 7
+setc h 8
+
+#loop
+pushc #back
+jmp #work
+
+#back
+pushv calls
+inc
+popv calls
+
+pushv calls
+pushc 200000
+
+cmpg
+jt #loop
+
+pushv calls
+println
+jmp #end
+
+#work
+save
+setc local 1
+pushv local
+pushv a
+add
+popv result
+ret
+jmp
+
+#end
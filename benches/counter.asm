@@ -0,0 +1,16 @@
+setc counter 0
+
+#loop
+pushv counter
+inc
+popv counter
+
+pushv counter
+pushc 1000000
+
+cmpg
+pushc #loop
+jt
+
+pushv counter
+println
@@ -0,0 +1,102 @@
+setc v00 0
+setc v01 1
+setc v02 2
+setc v03 3
+setc v04 4
+setc v05 5
+setc v06 6
+setc v07 7
+setc v08 8
+setc v09 9
+setc v10 10
+setc v11 11
+setc v12 12
+setc v13 13
+setc v14 14
+setc v15 15
+setc v16 16
+setc v17 17
+setc v18 18
+setc v19 19
+setc v20 20
+setc v21 21
+setc v22 22
+setc v23 23
+setc v24 24
+setc v25 25
+setc v26 26
+setc v27 27
+setc v28 28
+setc v29 29
+setc v30 30
+setc v31 31
+setc v32 32
+setc v33 33
+setc v34 34
+setc v35 35
+setc v36 36
+setc v37 37
+setc v38 38
+setc v39 39
+setc v40 40
+setc v41 41
+setc v42 42
+setc v43 43
+setc v44 44
+setc v45 45
+setc v46 46
+setc v47 47
+setc v48 48
+setc v49 49
+setc v50 50
+setc v51 51
+setc v52 52
+setc v53 53
+setc v54 54
+setc v55 55
+setc v56 56
+setc v57 57
+setc v58 58
+setc v59 59
+setc v60 60
+setc v61 61
+setc v62 62
+setc v63 63
+
+pushc 100000
+pushc #done
+jmp #sum
+
+#done
+println
+jmp #end
+
+#sum
+save
+popv back
+popv n
+
+pushv n
+pushc 0
+cmpe
+jt #base
+
+pushv n
+dec
+pushc #after
+jmp #sum
+
+#after
+pushv n
+add
+pushv back
+ret
+jmp
+
+#base
+pushc 0
+pushv back
+ret
+jmp
+
+#end
@@ -0,0 +1,34 @@
+#!/usr/bin/env bash
+# Assembles every benchmark, then times it and counts its allocations with a release build of Machina,
+# running it from the decoded instructions, straight from the bytecode with `--stream`, and with the register-based
+# interpreter with `--registers`.
+# Run from the repository root: ./benches/run.sh
+set -e
+
+# counting allocations makes allocating a bit slower, which the times include
+cargo build --release --quiet --features alloc-stats
+
+dir=$(mktemp -d)
+trap 'rm -rf "$dir"' EXIT
+
+TIMEFORMAT='%3Rs'
+
+cp benches/*.asm "$dir/"
+
+# a long program without loops, where loading is most of the work
+awk 'BEGIN {
+  print "setc total 0"
+  for (i = 0; i < 500000; i++) { print "pushv total"; print "pushc " i % 1000; print "add"; print "popv total" }
+  print "pushv total"; print "println"
+}' > "$dir/straight.asm"
+
+for f in "$dir"/*.asm; do
+  name=$(basename "$f" .asm)
+  target/release/machina assemble "$f"
+
+  for mode in --decoded --stream --registers; do
+    # the allocation count goes to the standard error, before the time
+    printf '%-10s %-12s' "$name" "$mode"
+    time target/release/machina run --alloc-stats ${mode/--decoded/} "$dir/$name.mch" 2>&1 > /dev/null | tr '\n' ' '
+  done
+done
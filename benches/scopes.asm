@@ -0,0 +1,34 @@
+setc limit 1000000
+setc a 1
+setc b 2
+setc c 3
+setc d 4
+setc e 5
+setc f 6
+setc g 7
+setc h 8
+
+save
+save
+save
+save
+save
+save
+save
+save
+
+setc counter 0
+
+#loop
+pushv counter
+inc
+popv counter
+
+pushv counter
+pushv limit
+
+cmpg
+jt #loop
+
+pushv counter
+println
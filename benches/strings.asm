@@ -0,0 +1,28 @@
+setc count 0
+setc greeting "hello"
+
+#loop
+pushc ", world"
+pushv greeting
+add
+popv message
+
+pushv message
+strlen
+pop
+
+pushc #key
+popv target
+
+pushv count
+inc
+popv count
+
+pushv count
+pushc 200000
+
+cmpg
+jt #loop
+
+pushv message
+println
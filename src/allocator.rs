@@ -0,0 +1,50 @@
+use std::{alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicUsize, Ordering}};
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+static LIVE: AtomicUsize = AtomicUsize::new(0); // bytes allocated and not freed yet
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting every allocation for `--alloc-stats`.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+    grow(layout.size());
+
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+
+    System.dealloc(ptr, layout)
+  }
+
+  // growing a vector or a string counts as a new allocation of its new size
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES.fetch_add(new_size, Ordering::Relaxed);
+    LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    grow(new_size);
+
+    System.realloc(ptr, layout, new_size)
+  }
+}
+
+/// Returns the number of allocations made so far, and their total size in bytes.
+pub fn allocations() -> (usize, usize) {
+  (ALLOCATIONS.load(Ordering::Relaxed), BYTES.load(Ordering::Relaxed))
+}
+
+/// Returns the most bytes that were ever allocated at once, since the start of the process.
+pub fn peak() -> usize {
+  PEAK.load(Ordering::Relaxed)
+}
+
+fn grow(size: usize) {
+  let live = LIVE.fetch_add(size, Ordering::Relaxed) + size;
+  PEAK.fetch_max(live, Ordering::Relaxed);
+}
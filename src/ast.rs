@@ -1,14 +1,16 @@
-use crate::compiler::encode_string;
+use std::{borrow::Cow, rc::Rc};
+
+use crate::{heap::{ListRef, MapRef, RecordRef}, util::is_identifier};
 
 #[derive(Debug)]
 pub struct AstNode {
-  pub data: AstNodeData,
+  pub data: AstNodeData<'static>,
   pub code: String,
   pub line: usize
 }
 
 impl AstNode {
-  pub fn new(data: AstNodeData, code: String, line: usize) -> Self {
+  pub fn new(data: AstNodeData<'static>, code: String, line: usize) -> Self {
     Self {
       data,
       code,
@@ -18,18 +20,28 @@ impl AstNode {
 }
 
 #[derive(Debug)]
-pub struct ReducedAstNode(pub AstNodeData);
+pub struct ReducedAstNode<'a>(pub AstNodeData<'a>);
+
+/// A variable operand. The assembler gives every variable name a slot, which is the
+/// index of the variable in each scope; the name is only kept for error messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Var {
+  pub name: Rc<str>,
+  pub slot: u32,
+}
 
+/// An instruction. Names are borrowed from the bytecode when it's loaded, and owned when
+/// they come from the source.
 #[derive(Debug, Clone)]
 #[repr(u8)]
-pub enum AstNodeData {
-  Label(String),
+pub enum AstNodeData<'a> {
+  Label(Cow<'a, str>),
 
   Pushc(Value),
-  Pushv(String),
+  Pushv(Var),
   
-  Setc(String, Value),
-  Popv(String),
+  Setc(Var, Value),
+  Popv(Var),
 
   Pop,
 
@@ -63,9 +75,323 @@ pub enum AstNodeData {
 
   Save,
   Ret,
+
+  Mod,
+  Pow,
+  Neg,
+  Abs,
+  Min,
+  Max,
+
+  Floor,
+  Ceil,
+  Round,
+  Sqrt,
+
+  Land,
+  Lor,
+  Lnot,
+  Lxor,
+
+  Dup,
+  Swap,
+  Over,
+  Rot,
+  Drop(u32),
+  Pick(u32),
+
+  Strlen,
+  Substr,
+  Charat,
+  Indexof,
+  Split,
+  Join,
+  Upper,
+  Lower,
+  Trim,
+  Replace,
+  Startswith,
+  Endswith,
+
+  Tonum,
+  Tostr,
+  Tobool,
+  Tolabel,
+  Typeof,
+
+  Listnew,
+  Listpush,
+  Listpop,
+  Listget,
+  Listset,
+  Listlen,
+  Listslice,
+  Listunpack,
+
+  Mapnew,
+  Mapget,
+  Mapset,
+  Maphas,
+  Mapdel,
+  Mapkeys,
+  Maplen,
+
+  Ref(Var),
+  Load,
+  Store,
+
+  Copy,
+
+  Isnil,
+
+  Struct(Cow<'a, str>, Vec<(Cow<'a, str>, Type)>),
+  New(Cow<'a, str>),
+  Getf(Cow<'a, str>),
+  Setf(Cow<'a, str>),
+
+  Decl(Var, Type),
+  Const(Var, Value),
+
+  // Superinstructions, which the optimizer puts in place of common runs of instructions so
+  // that they take one dispatch. Their opcodes come after the ones for shorter `pushc` and
+  // `setc` forms.
+  Incv(Var) = 98,
+  Decv(Var),
+  Jcmp(Comparison, Var, Value, Cow<'a, str>),
+}
+
+/// The comparison a `jcmp` instruction makes, with the opcode of the matching `cmp` instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Comparison {
+  G = 17,
+  Ge,
+  L,
+  Le,
+  E,
+  Ne,
+}
+
+impl Comparison {
+  pub fn from_opcode(opcode: u8) -> Option<Self> {
+    match opcode {
+      17 => Some(Comparison::G),
+      18 => Some(Comparison::Ge),
+      19 => Some(Comparison::L),
+      20 => Some(Comparison::Le),
+      21 => Some(Comparison::E),
+      22 => Some(Comparison::Ne),
+      _ => None,
+    }
+  }
+
+  /// The comparison made by a `cmp` instruction.
+  pub fn of(data: &AstNodeData) -> Option<Self> {
+    Self::from_opcode(data.discriminant())
+  }
+
+  /// The comparison of a `jcmp` mnemonic.
+  pub fn from_mnemonic(name: &str) -> Option<Self> {
+    match name {
+      "jcmpg" => Some(Comparison::G),
+      "jcmpge" => Some(Comparison::Ge),
+      "jcmpl" => Some(Comparison::L),
+      "jcmple" => Some(Comparison::Le),
+      "jcmpe" => Some(Comparison::E),
+      "jcmpne" => Some(Comparison::Ne),
+      _ => None,
+    }
+  }
+
+  /// The `cmp` instruction that makes the comparison.
+  pub fn instruction(self) -> AstNodeData<'static> {
+    match self {
+      Comparison::G => AstNodeData::Cmpg,
+      Comparison::Ge => AstNodeData::Cmpge,
+      Comparison::L => AstNodeData::Cmpl,
+      Comparison::Le => AstNodeData::Cmple,
+      Comparison::E => AstNodeData::Cmpe,
+      Comparison::Ne => AstNodeData::Cmpne,
+    }
+  }
 }
 
-impl AstNodeData {
+impl AstNodeData<'_> {
+  /// The mnemonic of the instruction, as written in assembly.
+  pub fn name(&self) -> &'static str {
+    match self {
+      AstNodeData::Label(_) => "label",
+      AstNodeData::Pushc(_) => "pushc",
+      AstNodeData::Pushv(_) => "pushv",
+      AstNodeData::Setc(..) => "setc",
+      AstNodeData::Popv(_) => "popv",
+      AstNodeData::Pop => "pop",
+      AstNodeData::Add => "add",
+      AstNodeData::Sub => "sub",
+      AstNodeData::Mul => "mul",
+      AstNodeData::Div => "div",
+      AstNodeData::Inc => "inc",
+      AstNodeData::Dec => "dec",
+      AstNodeData::Inputn => "inputn",
+      AstNodeData::Inputb => "inputb",
+      AstNodeData::Inputs => "inputs",
+      AstNodeData::Print => "print",
+      AstNodeData::Println => "println",
+      AstNodeData::Cmpg => "cmpg",
+      AstNodeData::Cmpge => "cmpge",
+      AstNodeData::Cmpl => "cmpl",
+      AstNodeData::Cmple => "cmple",
+      AstNodeData::Cmpe => "cmpe",
+      AstNodeData::Cmpne => "cmpne",
+      AstNodeData::Jmp => "jmp",
+      AstNodeData::Jt => "jt",
+      AstNodeData::Jf => "jf",
+      AstNodeData::Save => "save",
+      AstNodeData::Ret => "ret",
+      AstNodeData::Mod => "mod",
+      AstNodeData::Pow => "pow",
+      AstNodeData::Neg => "neg",
+      AstNodeData::Abs => "abs",
+      AstNodeData::Min => "min",
+      AstNodeData::Max => "max",
+      AstNodeData::Floor => "floor",
+      AstNodeData::Ceil => "ceil",
+      AstNodeData::Round => "round",
+      AstNodeData::Sqrt => "sqrt",
+      AstNodeData::Land => "land",
+      AstNodeData::Lor => "lor",
+      AstNodeData::Lnot => "lnot",
+      AstNodeData::Lxor => "lxor",
+      AstNodeData::Dup => "dup",
+      AstNodeData::Swap => "swap",
+      AstNodeData::Over => "over",
+      AstNodeData::Rot => "rot",
+      AstNodeData::Drop(_) => "drop",
+      AstNodeData::Pick(_) => "pick",
+      AstNodeData::Strlen => "strlen",
+      AstNodeData::Substr => "substr",
+      AstNodeData::Charat => "charat",
+      AstNodeData::Indexof => "indexof",
+      AstNodeData::Split => "split",
+      AstNodeData::Join => "join",
+      AstNodeData::Upper => "upper",
+      AstNodeData::Lower => "lower",
+      AstNodeData::Trim => "trim",
+      AstNodeData::Replace => "replace",
+      AstNodeData::Startswith => "startswith",
+      AstNodeData::Endswith => "endswith",
+      AstNodeData::Tonum => "tonum",
+      AstNodeData::Tostr => "tostr",
+      AstNodeData::Tobool => "tobool",
+      AstNodeData::Tolabel => "tolabel",
+      AstNodeData::Typeof => "typeof",
+      AstNodeData::Listnew => "listnew",
+      AstNodeData::Listpush => "listpush",
+      AstNodeData::Listpop => "listpop",
+      AstNodeData::Listget => "listget",
+      AstNodeData::Listset => "listset",
+      AstNodeData::Listlen => "listlen",
+      AstNodeData::Listslice => "listslice",
+      AstNodeData::Listunpack => "listunpack",
+      AstNodeData::Mapnew => "mapnew",
+      AstNodeData::Mapget => "mapget",
+      AstNodeData::Mapset => "mapset",
+      AstNodeData::Maphas => "maphas",
+      AstNodeData::Mapdel => "mapdel",
+      AstNodeData::Mapkeys => "mapkeys",
+      AstNodeData::Maplen => "maplen",
+      AstNodeData::Ref(_) => "ref",
+      AstNodeData::Load => "load",
+      AstNodeData::Store => "store",
+      AstNodeData::Copy => "copy",
+      AstNodeData::Isnil => "isnil",
+      AstNodeData::Struct(..) => ".struct",
+      AstNodeData::New(_) => "new",
+      AstNodeData::Getf(_) => "getf",
+      AstNodeData::Setf(_) => "setf",
+      AstNodeData::Decl(..) => "decl",
+      AstNodeData::Const(..) => "const",
+
+      AstNodeData::Incv(_) => "incv",
+      AstNodeData::Decv(_) => "decv",
+      AstNodeData::Jcmp(Comparison::G, ..) => "jcmpg",
+      AstNodeData::Jcmp(Comparison::Ge, ..) => "jcmpge",
+      AstNodeData::Jcmp(Comparison::L, ..) => "jcmpl",
+      AstNodeData::Jcmp(Comparison::Le, ..) => "jcmple",
+      AstNodeData::Jcmp(Comparison::E, ..) => "jcmpe",
+      AstNodeData::Jcmp(Comparison::Ne, ..) => "jcmpne",
+    }
+  }
+
+  /// The instruction as it would be written in the source.
+  pub fn to_source(&self) -> String {
+    match self {
+      AstNodeData::Label(name) => name.to_string(),
+
+      AstNodeData::Pushc(value) => format!("pushc {}", value.as_literal()),
+      AstNodeData::Setc(var, value)
+      | AstNodeData::Const(var, value) => format!("{} {} {}", self.name(), var.name, value.as_literal()),
+
+      AstNodeData::Pushv(var)
+      | AstNodeData::Popv(var)
+      | AstNodeData::Ref(var) => format!("{} {}", self.name(), var.name),
+
+      AstNodeData::Drop(n)
+      | AstNodeData::Pick(n) => format!("{} {}", self.name(), n),
+
+      AstNodeData::Struct(name, fields) => {
+        let mut parts = vec![self.name().to_string(), name.to_string()];
+        parts.extend(fields.iter().map(|(f, ty)| format!("{}:{}", f, ty.name())));
+
+        parts.join(" ")
+      }
+
+      AstNodeData::New(name)
+      | AstNodeData::Getf(name)
+      | AstNodeData::Setf(name) => format!("{} {}", self.name(), name),
+
+      AstNodeData::Decl(var, ty) => format!("decl {} {}", var.name, ty.name()),
+
+      AstNodeData::Incv(var)
+      | AstNodeData::Decv(var) => format!("{} {}", self.name(), var.name),
+      AstNodeData::Jcmp(_, var, value, label) => format!("{} {} {} {}", self.name(), var.name, value.as_literal(), label),
+
+      _ => self.name().to_string(),
+    }
+  }
+
+  /// The instructions a superinstruction stands for, which do the same when run one after another.
+  pub fn expand(&self) -> Option<Vec<AstNodeData<'static>>> {
+    match self {
+      AstNodeData::Incv(var) => Some(vec![AstNodeData::Pushv(var.clone()), AstNodeData::Inc, AstNodeData::Popv(var.clone())]),
+      AstNodeData::Decv(var) => Some(vec![AstNodeData::Pushv(var.clone()), AstNodeData::Dec, AstNodeData::Popv(var.clone())]),
+      AstNodeData::Jcmp(cmp, var, value, label) => Some(vec![
+        AstNodeData::Pushv(var.clone()),
+        AstNodeData::Pushc(value.clone()),
+        cmp.instruction(),
+        AstNodeData::Pushc(Value::Label(label.as_ref().into())),
+        AstNodeData::Jt,
+      ]),
+      _ => None,
+    }
+  }
+
+  /// The variable the instruction works on, if any.
+  pub fn var(&self) -> Option<&Var> {
+    match self {
+      AstNodeData::Pushv(var)
+      | AstNodeData::Setc(var, _)
+      | AstNodeData::Popv(var)
+      | AstNodeData::Ref(var)
+      | AstNodeData::Decl(var, _)
+      | AstNodeData::Const(var, _)
+      | AstNodeData::Incv(var)
+      | AstNodeData::Decv(var)
+      | AstNodeData::Jcmp(_, var, ..) => Some(var),
+      _ => None,
+    }
+  }
+
   pub fn discriminant(&self) -> u8 {
     // Safety: got from <https://doc.rust-lang.org/std/mem/fn.discriminant.html>
     unsafe { *<*const _>::from(self).cast::<u8>() }
@@ -76,43 +402,178 @@ impl AstNodeData {
 #[repr(u8)]
 pub enum Value {
   Num(f64),
-  Str(String),
+  Str(Rc<str>), // strings are immutable, so copies of a value share them
   Bool(bool),
-  Label(String),
-  // TODO! Ref(String)
+  Label(Rc<str>),
+  List(ListRef),
+  Map(MapRef),
+  Ref(Var, usize), // the variable and the id of the scope it lives in; only created at runtime
+  Nil,
+  Record(RecordRef),
 }
 
 impl Value {
   pub fn as_str(&self) -> String {
+    self.format(Format::Plain, &mut vec![])
+  }
+
+  pub fn as_str_debug(&self) -> String {
+    self.format(Format::Debug, &mut vec![])
+  }
+
+  /// The value as it would be written in the source, for the constants the assembler can hold.
+  pub fn as_literal(&self) -> String {
+    self.format(Format::Literal, &mut vec![])
+  }
+
+  /// `seen` holds the lists and maps being formatted, so that one containing
+  /// itself is printed as `[...]` or `{...}` instead of recursing forever.
+  fn format(&self, format: Format, seen: &mut Vec<*const ()>) -> String {
+    let prefix = if let Format::Debug = format { format!("{} ", self.type_name()) } else { String::new() };
+    let inner = if let Format::Debug = format { Format::Debug } else { Format::Literal };
+
     match self {
-      Value::Num(n) => format!("{}", n),
-      Value::Str(s) => s.clone(),
-      Value::Bool(b) => format!("{}", b),
-      Value::Label(l) => l.clone(),
+      Value::Num(n) => format!("{}{}", prefix, n),
+      Value::Str(s) => match format {
+        Format::Plain => s.to_string(),
+        _ => format!("{}\"{}\"", prefix, s),
+      },
+      Value::Bool(b) => format!("{}{}", prefix, b),
+      Value::Label(l) => format!("{}{}", prefix, l),
+
+      Value::List(list) => {
+        let ptr = Rc::as_ptr(list) as *const ();
+
+        if seen.contains(&ptr) {
+          return format!("{}[...]", prefix);
+        }
+
+        seen.push(ptr);
+        let items: Vec<String> = list.borrow().iter().map(|v| v.format(inner, seen)).collect();
+        seen.pop();
+
+        format!("{}[{}]", prefix, items.join(", "))
+      }
+
+      Value::Map(map) => {
+        let ptr = Rc::as_ptr(map) as *const ();
+
+        if seen.contains(&ptr) {
+          return format!("{}{{...}}", prefix);
+        }
+
+        seen.push(ptr);
+        let entries: Vec<String> = map.borrow().iter().map(|(k, v)| format!("\"{}\": {}", k, v.format(inner, seen))).collect();
+        seen.pop();
+
+        format!("{}{{{}}}", prefix, entries.join(", "))
+      }
+
+      Value::Ref(var, _) => format!("{}&{}", prefix, var.name),
+      Value::Nil => "nil".into(),
+
+      Value::Record(record) => {
+        let ptr = Rc::as_ptr(record) as *const ();
+        let name = record.borrow().name.clone();
+
+        if seen.contains(&ptr) {
+          return format!("{}{} {{...}}", prefix, name);
+        }
+
+        seen.push(ptr);
+        let fields: Vec<String> = record.borrow().fields.iter().map(|(k, v)| format!("{}: {}", k, v.format(inner, seen))).collect();
+        seen.pop();
+
+        format!("{}{} {{{}}}", prefix, name, fields.join(", "))
+      }
     }
   }
 
-  pub fn as_str_debug(&self) -> String {
+  pub fn type_name(&self) -> &'static str {
     match self {
-      Value::Num(n) => format!("num {}", n),
-      Value::Str(s) => format!("str \"{}\"", s),
-      Value::Bool(b) => format!("bool {}", b),
-      Value::Label(l) => format!("label {}", l),
+      Value::Num(_) => "num",
+      Value::Str(_) => "str",
+      Value::Bool(_) => "bool",
+      Value::Label(_) => "label",
+      Value::List(_) => "list",
+      Value::Map(_) => "map",
+      Value::Ref(..) => "ref",
+      Value::Nil => "nil",
+      Value::Record(_) => "record",
     }
   }
 
-  pub fn encode(&self) -> Vec<u8> {
-    let mut output = vec![];
-    output.push(self.discriminant());
+  pub fn discriminant(&self) -> u8 {
+    // Safety: got from <https://doc.rust-lang.org/std/mem/fn.discriminant.html>
+    unsafe { *<*const _>::from(self).cast::<u8>() }
+  }
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+  Plain,
+  Literal, // strings are quoted, as they would be written in a literal
+  Debug,
+}
+
+/// The type of a value, as written in declarations.
+#[derive(Debug, Clone, PartialEq)]
+#[repr(u8)]
+pub enum Type {
+  Num,
+  Str,
+  Bool,
+  Label,
+  List,
+  Map,
+  Ref,
+  Nil,
+  Record, // a record of any struct
+  Any,
+  Struct(String),
+}
+
+impl Type {
+  /// Parses a type name; any other identifier names a struct.
+  pub fn from_name(s: &str) -> Option<Type> {
+    match s {
+      "num" => Some(Type::Num),
+      "str" => Some(Type::Str),
+      "bool" => Some(Type::Bool),
+      "label" => Some(Type::Label),
+      "list" => Some(Type::List),
+      "map" => Some(Type::Map),
+      "ref" => Some(Type::Ref),
+      "nil" => Some(Type::Nil),
+      "record" => Some(Type::Record),
+      "any" => Some(Type::Any),
+      s if !s.is_empty() && is_identifier(s) => Some(Type::Struct(s.into())),
+      _ => None,
+    }
+  }
 
+  pub fn name(&self) -> &str {
     match self {
-      Value::Num(n) => output.extend_from_slice(&n.to_le_bytes()),
-      Value::Str(s) => encode_string(&mut output, s),
-      Value::Bool(b) => output.push(*b as u8),
-      Value::Label(l) => encode_string(&mut output, l),
+      Type::Num => "num",
+      Type::Str => "str",
+      Type::Bool => "bool",
+      Type::Label => "label",
+      Type::List => "list",
+      Type::Map => "map",
+      Type::Ref => "ref",
+      Type::Nil => "nil",
+      Type::Record => "record",
+      Type::Any => "any",
+      Type::Struct(name) => name,
     }
+  }
 
-    output
+  pub fn matches(&self, value: &Value) -> bool {
+    match (self, value) {
+      (Type::Any, _) => true,
+      (Type::Struct(name), Value::Record(record)) => *record.borrow().name == **name,
+      (t, v) => t.name() == v.type_name(),
+    }
   }
 
   pub fn discriminant(&self) -> u8 {
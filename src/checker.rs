@@ -0,0 +1,607 @@
+use std::{borrow::Cow, collections::{BTreeMap, HashMap, HashSet, VecDeque}};
+
+use crate::{ast::{AstNode, AstNodeData, Type, Value}, optimizer, util::print_error};
+
+/// What is known about a value on the operation stack.
+#[derive(Debug, Clone, PartialEq)]
+struct Slot {
+  ty: Type, // `Type::Any` when unknown
+  label: Option<String>, // the label itself, if it is a known constant
+}
+
+impl Slot {
+  fn of(ty: Type) -> Self {
+    Self { ty, label: None }
+  }
+
+  fn any() -> Self {
+    Self::of(Type::Any)
+  }
+
+  fn merge(&self, other: &Slot) -> Slot {
+    if self == other {
+      self.clone()
+    }
+    else if self.ty == other.ty {
+      Slot::of(self.ty.clone())
+    }
+    else {
+      Slot::any()
+    }
+  }
+}
+
+/// What is known about the program state right before an instruction runs.
+#[derive(Debug, Clone, PartialEq, Default)]
+struct State {
+  stack: Vec<Slot>,
+  open: bool, // whether there may be more (unknown) values under `stack`
+  vars: HashMap<String, Type>,
+  declared: HashMap<String, Type>, // declarations of the current scope
+  constants: HashSet<String>,
+}
+
+impl State {
+  fn merge(&self, other: &State) -> State {
+    let len = self.stack.len().min(other.stack.len());
+
+    let stack = self.stack[self.stack.len() - len..].iter()
+      .zip(&other.stack[other.stack.len() - len..])
+      .map(|(a, b)| a.merge(b))
+      .collect();
+
+    let vars = self.vars.iter()
+      .filter_map(|(k, a)| other.vars.get(k).map(|b| (k.clone(), if a == b { a.clone() } else { Type::Any })))
+      .collect();
+
+    let declared = self.declared.iter()
+      .filter(|(k, a)| other.declared.get(*k) == Some(a))
+      .map(|(k, a)| (k.clone(), a.clone()))
+      .collect();
+
+    State {
+      stack,
+      open: self.open || other.open || self.stack.len() != other.stack.len(),
+      vars,
+      declared,
+      constants: self.constants.intersection(&other.constants).cloned().collect(),
+    }
+  }
+
+  fn push(&mut self, ty: Type) {
+    self.stack.push(Slot::of(ty));
+  }
+
+  fn pop(&mut self, inst: &str) -> Result<Slot, String> {
+    match self.stack.pop() {
+      Some(s) => Ok(s),
+      None if self.open => Ok(Slot::any()),
+      None => Err(format!("'{}' pops the operation stack, which is always empty here", inst)),
+    }
+  }
+
+  /// Pops a value, checking that it can be of type `ty`.
+  fn pop_as(&mut self, inst: &str, ty: Type, operand: usize) -> Result<Slot, String> {
+    let slot = self.pop(inst)?;
+
+    if compatible(&slot.ty, &ty) {
+      Ok(slot)
+    }
+    else {
+      Err(format!("'{}' expects {} as its {} operand, but it is always {} here", inst, ty.name(), ordinal(operand), slot.ty.name()))
+    }
+  }
+
+  /// Pops a value, checking that it can be a record.
+  fn pop_record(&mut self, inst: &str) -> Result<Option<String>, String> {
+    let slot = self.pop(inst)?;
+
+    match slot.ty {
+      Type::Struct(name) => Ok(Some(name)),
+      Type::Record | Type::Any => Ok(None),
+      ty => Err(format!("'{}' expects a record as its first operand, but it is always {} here", inst, ty.name())),
+    }
+  }
+
+  /// Forgets everything below the top `keep` values.
+  fn forget_below(&mut self, keep: usize) {
+    let len = self.stack.len();
+    self.stack.drain(..len.saturating_sub(keep));
+    self.open = true;
+  }
+
+  fn assign(&mut self, inst: &str, var: &str, ty: Type) -> Result<(), String> {
+    if self.constants.contains(var) {
+      return Err(format!("'{}' assigns to '{}', which is a constant", inst, var));
+    }
+
+    if let Some(declared) = self.declared.get(var) {
+      if !compatible(declared, &ty) {
+        return Err(format!("'{}' assigns {} to '{}', which was declared as {}", inst, ty.name(), var, declared.name()));
+      }
+    }
+
+    self.vars.insert(var.into(), ty);
+    Ok(())
+  }
+}
+
+/// Checks the types of an assembled program without running it, printing every error found.
+///
+/// The checker follows every path through the program, tracking the types of the values on
+/// the operation stack and in variables. Whatever it can't know for sure (such as the target
+/// of a jump to a label read from a variable) is assumed to be correct, so every error it
+/// reports is a place where the program would fail if it got there.
+pub fn check(ast: &[AstNode]) -> Result<(), ()> {
+  let mut labels = HashMap::new();
+  let mut structs = HashMap::new();
+
+  for (i, node) in ast.iter().enumerate() {
+    match &node.data {
+      AstNodeData::Label(name) => { labels.insert(name.as_ref(), i); },
+      AstNodeData::Struct(name, fields) => { structs.insert(name.as_ref(), fields.as_slice()); },
+      _ => {}
+    }
+  }
+
+  // labels in constants, including inside lists and maps, can be the target of any jump the
+  // checker can't resolve, and so can every label once strings can be made into labels
+  let mut values = HashSet::new();
+
+  for node in ast {
+    if let AstNodeData::Pushc(value) | AstNodeData::Setc(_, value) | AstNodeData::Const(_, value) = &node.data {
+      optimizer::collect_labels(value, &mut values);
+    }
+  }
+
+  let mut escaping: Vec<usize> = if ast.iter().any(|n| matches!(n.data, AstNodeData::Tolabel)) {
+    labels.values().copied().collect()
+  }
+  else {
+    values.iter().filter_map(|l| labels.get(l.as_str()).copied()).collect()
+  };
+
+  escaping.sort();
+  escaping.dedup();
+
+  let unknown = State { open: true, ..Default::default() };
+
+  let mut states: Vec<Option<State>> = vec![None; ast.len()];
+  let mut errors: BTreeMap<usize, String> = BTreeMap::new();
+  let mut pending = VecDeque::new();
+
+  if !ast.is_empty() {
+    states[0] = Some(State::default());
+    pending.push_back(0);
+  }
+
+  while let Some(i) = pending.pop_front() {
+    let mut state = states[i].clone().unwrap();
+
+    let successors = match step(&ast[i].data, &mut state, &labels, &structs) {
+      Ok(Jump::Next) => vec![(i + 1, state)],
+      Ok(Jump::To(target)) => vec![(target, state)],
+      Ok(Jump::Branch(target)) => vec![(target, state.clone()), (i + 1, state)],
+      Ok(Jump::Unknown { fallthrough }) => {
+        let mut s: Vec<(usize, State)> = escaping.iter().map(|t| (*t, unknown.clone())).collect();
+
+        if fallthrough {
+          s.push((i + 1, state));
+        }
+
+        s
+      }
+      Err(e) => {
+        errors.entry(i).or_insert(e);
+
+        // keep checking the rest of the program, without assuming anything about the stack
+        vec![(i + 1, State { stack: vec![], open: true, ..states[i].clone().unwrap() })]
+      }
+    };
+
+    for (target, state) in successors {
+      if target >= ast.len() {
+        continue;
+      }
+
+      let merged = match &states[target] {
+        Some(s) => s.merge(&state),
+        None => state,
+      };
+
+      if states[target].as_ref() != Some(&merged) {
+        states[target] = Some(merged);
+        pending.push_back(target);
+      }
+    }
+  }
+
+  for (i, e) in &errors {
+    print_error(e, &ast[*i].code, ast[*i].line);
+  }
+
+  if errors.is_empty() { Ok(()) } else { Err(()) }
+}
+
+enum Jump {
+  Next,
+  To(usize),
+  Branch(usize),
+  Unknown { fallthrough: bool },
+}
+
+fn step(data: &AstNodeData, state: &mut State, labels: &HashMap<&str, usize>, structs: &HashMap<&str, &[(Cow<str>, Type)]>) -> Result<Jump, String> {
+  let inst = data.name();
+
+  // instructions that only pop values of fixed types and push values of fixed types
+  if let Some((inputs, outputs)) = signature(data) {
+    for (i, ty) in inputs.into_iter().enumerate() {
+      state.pop_as(inst, ty, i)?;
+    }
+
+    for ty in outputs {
+      state.push(ty);
+    }
+
+    return Ok(Jump::Next);
+  }
+
+  match data {
+    AstNodeData::Label(_) | AstNodeData::Struct(..) => {}
+
+    AstNodeData::Pushc(value) => state.stack.push(slot_of(value)),
+    AstNodeData::Pushv(var) => state.push(state.vars.get(&*var.name).cloned().unwrap_or(Type::Any)),
+
+    AstNodeData::Setc(var, value) => state.assign(inst, &var.name, slot_of(value).ty)?,
+    AstNodeData::Popv(var) => {
+      let slot = state.pop(inst)?;
+      state.assign(inst, &var.name, slot.ty)?;
+    }
+
+    AstNodeData::Add => {
+      let a = state.pop(inst)?.ty;
+      let b = state.pop(inst)?.ty;
+
+      let ty = match (a, b) {
+        (Type::Any, Type::Any) => Type::Any,
+        (Type::Any, t) | (t, Type::Any) if t == Type::Num || t == Type::Str => t,
+        (a, b) if a == b && (a == Type::Num || a == Type::Str) => a,
+        (a, b) => return Err(format!("'add' can't add {} and {}", a.name(), b.name())),
+      };
+
+      state.push(ty);
+    }
+
+    AstNodeData::Cmpg | AstNodeData::Cmpge | AstNodeData::Cmpl | AstNodeData::Cmple => {
+      let a = state.pop(inst)?.ty;
+      let b = state.pop(inst)?.ty;
+
+      match (a, b) {
+        (Type::Any, Type::Any) => {}
+        (Type::Any, t) | (t, Type::Any) if t == Type::Num || t == Type::Str => {}
+        (a, b) if a == b && (a == Type::Num || a == Type::Str) => {}
+        (a, b) => return Err(format!("'{}' can't order {} and {} (only numbers and strings can be ordered, and both must be of the same type)", inst, a.name(), b.name())),
+      }
+
+      state.push(Type::Bool);
+    }
+
+    AstNodeData::Cmpe | AstNodeData::Cmpne => {
+      let a = state.pop(inst)?.ty;
+      let b = state.pop(inst)?.ty;
+
+      if a != Type::Nil && b != Type::Nil && !compatible(&a, &b) {
+        return Err(format!("'{}' can't compare {} and {} (they must be of the same type)", inst, a.name(), b.name()));
+      }
+
+      state.push(Type::Bool);
+    }
+
+    AstNodeData::Jmp | AstNodeData::Jt | AstNodeData::Jf => {
+      let label = state.pop_as(inst, Type::Label, 0)?;
+
+      if !matches!(data, AstNodeData::Jmp) {
+        state.pop_as(inst, Type::Bool, 1)?;
+      }
+
+      return match label.label {
+        Some(l) => match labels.get(l.as_str()) {
+          Some(target) if matches!(data, AstNodeData::Jmp) => Ok(Jump::To(*target)),
+          Some(target) => Ok(Jump::Branch(*target)),
+          None => Err(format!("'{}' jumps to label {}, which doesn't exist", inst, l)),
+        },
+        None => Ok(Jump::Unknown { fallthrough: !matches!(data, AstNodeData::Jmp) }),
+      };
+    }
+
+    AstNodeData::Save => {
+      // assignments create new variables in the new scope
+      state.declared.clear();
+      state.constants.clear();
+    }
+    AstNodeData::Ret => {
+      // the variables are back to what they were before the matching 'save', which isn't tracked
+      state.vars.clear();
+      state.declared.clear();
+      state.constants.clear();
+    }
+
+    AstNodeData::Dup => {
+      let a = state.pop(inst)?;
+      state.stack.push(a.clone());
+      state.stack.push(a);
+    }
+    AstNodeData::Swap => {
+      let a = state.pop(inst)?;
+      let b = state.pop(inst)?;
+      state.stack.push(a);
+      state.stack.push(b);
+    }
+    AstNodeData::Over => {
+      let a = state.pop(inst)?;
+      let b = state.pop(inst)?;
+      state.stack.push(b.clone());
+      state.stack.push(a);
+      state.stack.push(b);
+    }
+    AstNodeData::Rot => {
+      let a = state.pop(inst)?;
+      let b = state.pop(inst)?;
+      let c = state.pop(inst)?;
+      state.stack.push(b);
+      state.stack.push(a);
+      state.stack.push(c);
+    }
+    AstNodeData::Drop(n) => {
+      for _ in 0..*n {
+        state.pop(inst)?;
+      }
+    }
+    AstNodeData::Pick(n) => {
+      let n = *n as usize;
+
+      let slot = match state.stack.len().checked_sub(n + 1) {
+        Some(i) => state.stack[i].clone(),
+        None if state.open => Slot::any(),
+        None => return Err(format!("'pick' reads item {} of the operation stack, which only has {} items here", n, state.stack.len())),
+      };
+
+      state.stack.push(slot);
+    }
+
+    AstNodeData::Split => {
+      state.pop_as(inst, Type::Str, 0)?;
+      state.pop_as(inst, Type::Str, 1)?;
+
+      state.forget_below(0);
+      state.push(Type::Num);
+    }
+    AstNodeData::Join => {
+      state.pop_as(inst, Type::Str, 0)?;
+      state.pop_as(inst, Type::Num, 1)?;
+
+      state.forget_below(0);
+      state.push(Type::Str);
+    }
+
+    AstNodeData::Tonum | AstNodeData::Tobool | AstNodeData::Tolabel => {
+      state.pop(inst)?;
+      state.push(Type::Any);
+      state.push(Type::Bool);
+    }
+
+    AstNodeData::Listpop => {
+      state.pop_as(inst, Type::List, 0)?;
+      state.push(Type::List);
+      state.push(Type::Any);
+    }
+    AstNodeData::Listunpack => {
+      state.pop_as(inst, Type::List, 0)?;
+
+      state.forget_below(0);
+      state.push(Type::Num);
+    }
+
+    AstNodeData::Ref(_) => state.push(Type::Ref),
+    AstNodeData::Store => {
+      state.pop_as(inst, Type::Ref, 0)?;
+      state.pop(inst)?;
+
+      // the reference may point to any variable
+      let declared = &state.declared;
+      state.vars.retain(|k, _| declared.contains_key(k));
+    }
+
+    AstNodeData::Copy => {
+      let a = state.pop(inst)?;
+      state.stack.push(Slot::of(a.ty));
+    }
+
+    AstNodeData::New(name) => {
+      let fields = match structs.get(name.as_ref()) {
+        Some(f) => *f,
+        None => return Err(format!("'new' creates a record of struct '{}', which doesn't exist", name)),
+      };
+
+      for (field, ty) in fields {
+        let slot = state.pop(inst)?;
+
+        if !compatible(&slot.ty, ty) {
+          return Err(format!("'new' sets field '{}' of struct '{}' to {}, but it was declared as {}", field, name, slot.ty.name(), ty.name()));
+        }
+      }
+
+      state.push(Type::Struct(name.to_string()));
+    }
+    AstNodeData::Getf(field) => {
+      let ty = match state.pop_record(inst)? {
+        Some(name) => field_type(structs, &name, field)?,
+        None => Type::Any,
+      };
+
+      state.push(ty);
+    }
+    AstNodeData::Setf(field) => {
+      let record = state.pop_record(inst)?;
+      let slot = state.pop(inst)?;
+
+      if let Some(name) = record {
+        let ty = field_type(structs, &name, field)?;
+
+        if !compatible(&slot.ty, &ty) {
+          return Err(format!("'setf' sets field '{}' of struct '{}' to {}, but it was declared as {}", field, name, slot.ty.name(), ty.name()));
+        }
+
+        state.push(Type::Struct(name));
+      }
+      else {
+        state.push(Type::Any);
+      }
+    }
+
+    AstNodeData::Decl(var, ty) => {
+      state.declared.insert(var.name.to_string(), ty.clone());
+      state.vars.remove(&*var.name);
+    }
+    AstNodeData::Const(var, value) => {
+      state.declared.insert(var.name.to_string(), Type::Any);
+      state.constants.insert(var.name.to_string());
+      state.vars.insert(var.name.to_string(), slot_of(value).ty);
+    }
+
+    AstNodeData::Incv(_) | AstNodeData::Decv(_) | AstNodeData::Jcmp(..) => {
+      let mut jump = Jump::Next;
+
+      for data in data.expand().expect("superinstructions expand") {
+        jump = step(&data, state, labels, structs).map_err(|e| format!("{} (in '{}')", e, inst))?;
+      }
+
+      return Ok(jump);
+    }
+
+    _ => unreachable!("'{}' has a fixed signature", inst),
+  }
+
+  Ok(Jump::Next)
+}
+
+/// Returns the types of the values an instruction pops (in order) and pushes, if they are fixed.
+fn signature(data: &AstNodeData) -> Option<(Vec<Type>, Vec<Type>)> {
+  use Type::*;
+
+  let (inputs, outputs) = match data {
+    AstNodeData::Pop | AstNodeData::Print | AstNodeData::Println => (vec![Any], vec![]),
+
+    AstNodeData::Sub | AstNodeData::Mul | AstNodeData::Div
+    | AstNodeData::Mod | AstNodeData::Pow | AstNodeData::Min | AstNodeData::Max => (vec![Num, Num], vec![Num]),
+
+    AstNodeData::Inc | AstNodeData::Dec | AstNodeData::Neg | AstNodeData::Abs
+    | AstNodeData::Floor | AstNodeData::Ceil | AstNodeData::Round | AstNodeData::Sqrt => (vec![Num], vec![Num]),
+
+    AstNodeData::Inputn => (vec![], vec![Num]),
+    AstNodeData::Inputb => (vec![], vec![Bool]),
+    AstNodeData::Inputs => (vec![], vec![Str]),
+
+    AstNodeData::Land | AstNodeData::Lor | AstNodeData::Lxor => (vec![Bool, Bool], vec![Bool]),
+    AstNodeData::Lnot => (vec![Bool], vec![Bool]),
+
+    AstNodeData::Strlen => (vec![Str], vec![Num]),
+    AstNodeData::Substr => (vec![Str, Num, Num], vec![Str]),
+    AstNodeData::Charat => (vec![Str, Num], vec![Str]),
+    AstNodeData::Indexof => (vec![Str, Str], vec![Num]),
+    AstNodeData::Upper | AstNodeData::Lower | AstNodeData::Trim => (vec![Str], vec![Str]),
+    AstNodeData::Replace => (vec![Str, Str, Str], vec![Str]),
+    AstNodeData::Startswith | AstNodeData::Endswith => (vec![Str, Str], vec![Bool]),
+
+    AstNodeData::Tostr | AstNodeData::Typeof => (vec![Any], vec![Str]),
+
+    AstNodeData::Listnew => (vec![], vec![List]),
+    AstNodeData::Listpush => (vec![List, Any], vec![List]),
+    AstNodeData::Listget => (vec![List, Num], vec![Any]),
+    AstNodeData::Listset => (vec![List, Num, Any], vec![List]),
+    AstNodeData::Listlen => (vec![List], vec![Num]),
+    AstNodeData::Listslice => (vec![List, Num, Num], vec![List]),
+
+    AstNodeData::Mapnew => (vec![], vec![Map]),
+    AstNodeData::Mapget => (vec![Map, Str], vec![Any]),
+    AstNodeData::Mapset => (vec![Map, Str, Any], vec![Map]),
+    AstNodeData::Maphas => (vec![Map, Str], vec![Bool]),
+    AstNodeData::Mapdel => (vec![Map, Str], vec![Map]),
+    AstNodeData::Mapkeys => (vec![Map], vec![List]),
+    AstNodeData::Maplen => (vec![Map], vec![Num]),
+
+    AstNodeData::Load => (vec![Ref], vec![Any]),
+    AstNodeData::Isnil => (vec![Any], vec![Bool]),
+
+    _ => return None,
+  };
+
+  Some((inputs, outputs))
+}
+
+/// Whether a value of one type can be a value of the other: `any` can be anything, and `record`
+/// can be a record of any struct.
+fn compatible(a: &Type, b: &Type) -> bool {
+  match (a, b) {
+    (Type::Any, _) | (_, Type::Any) => true,
+    (Type::Record, Type::Record | Type::Struct(_)) | (Type::Struct(_), Type::Record) => true,
+    (a, b) => a == b,
+  }
+}
+
+fn slot_of(value: &Value) -> Slot {
+  let ty = Type::from_name(value.type_name()).unwrap_or(Type::Any);
+
+  match value {
+    Value::Label(l) => Slot { ty, label: Some(l.to_string()) },
+    _ => Slot::of(ty),
+  }
+}
+
+fn field_type(structs: &HashMap<&str, &[(Cow<str>, Type)]>, name: &str, field: &str) -> Result<Type, String> {
+  structs.get(name)
+    .and_then(|fields| fields.iter().find(|(f, _)| f == field))
+    .map(|(_, ty)| ty.clone())
+    .ok_or_else(|| format!("Struct '{}' has no field '{}'", name, field))
+}
+
+fn ordinal(n: usize) -> &'static str {
+  match n {
+    0 => "first",
+    1 => "second",
+    _ => "third",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::check;
+  use crate::parser::parse;
+
+  // subtracting from a string, behind a label only an unresolved jump gets to
+  const BEHIND: &str = "#bad\npushc \"s\"\npushc 1\nsub\n";
+
+  #[test]
+  fn follows_jumps_to_labels_in_lists() {
+    let source = format!("setc targets [#bad]\npushc 0\npushv targets\nlistget\njmp\n{BEHIND}");
+    assert!(check(&parse(&source).unwrap()).is_err());
+  }
+
+  #[test]
+  fn follows_jumps_to_labels_in_maps() {
+    let source = format!("setc targets {{\"k\": #bad}}\npushc \"k\"\npushv targets\nmapget\njmp\n{BEHIND}");
+    assert!(check(&parse(&source).unwrap()).is_err());
+  }
+
+  #[test]
+  fn follows_jumps_to_any_label_with_tolabel() {
+    let source = format!("pushc \"#bad\"\ntolabel\npop\njmp\n{BEHIND}");
+    assert!(check(&parse(&source).unwrap()).is_err());
+  }
+
+  #[test]
+  fn accepts_code_behind_labels_in_lists() {
+    let source = "setc targets [#ok]\npushc 0\npushv targets\nlistget\njmp\n#ok\npushc 2\npushc 1\nsub\nprintln\n";
+    assert!(check(&parse(source).unwrap()).is_ok());
+  }
+}
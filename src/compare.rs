@@ -0,0 +1,84 @@
+use std::{cmp::Ordering, collections::HashSet};
+
+use crate::{ast::Value, heap::address};
+
+/// Checks whether two values are equal.
+///
+/// `nil` can be compared with every value and is only equal to itself. Other values must be
+/// of the same type: numbers, strings, booleans and labels are compared by value, references
+/// by the variable they point to, and lists, maps and records by their contents (records must
+/// also be instances of the same struct). Comparing values of different types is an error.
+pub fn equals(a: &Value, b: &Value) -> Result<bool, ()> {
+  equals_seen(a, b, &mut HashSet::new())
+}
+
+/// Orders two values, for `cmpg`, `cmpge`, `cmpl` and `cmple`.
+///
+/// Numbers are ordered by value and strings lexicographically (by Unicode code point). Any
+/// other pair of values can't be ordered and is an error. Returns `None` when a number is NaN,
+/// which makes every ordered comparison false.
+pub fn order(a: &Value, b: &Value) -> Result<Option<Ordering>, ()> {
+  match (a, b) {
+    (Value::Num(a), Value::Num(b)) => Ok(a.partial_cmp(b)),
+    (Value::Str(a), Value::Str(b)) => Ok(Some(a.cmp(b))),
+    _ => Err(()),
+  }
+}
+
+// `seen` holds the pairs of objects being compared further up, so that cyclic values
+// are considered equal when they have the same shape instead of looping forever
+fn equals_seen(a: &Value, b: &Value, seen: &mut HashSet<(usize, usize)>) -> Result<bool, ()> {
+  if let (Some(x), Some(y)) = (address(a), address(b)) {
+    if x == y || !seen.insert((x, y)) {
+      return Ok(true);
+    }
+  }
+
+  match (a, b) {
+    (Value::Nil, b) => Ok(matches!(b, Value::Nil)),
+    (_, Value::Nil) => Ok(false),
+
+    (Value::Num(a), Value::Num(b)) => Ok(a == b),
+    (Value::Str(a), Value::Str(b)) => Ok(a == b),
+    (Value::Bool(a), Value::Bool(b)) => Ok(a == b),
+    (Value::Label(a), Value::Label(b)) => Ok(a == b),
+    (Value::Ref(a, x), Value::Ref(b, y)) => Ok(a == b && x == y),
+
+    (Value::List(a), Value::List(b)) => {
+      let (a, b) = (a.borrow(), b.borrow());
+
+      if a.len() != b.len() {
+        return Ok(false);
+      }
+
+      Ok(all_equal(a.iter().zip(b.iter()), seen))
+    }
+
+    (Value::Map(a), Value::Map(b)) => {
+      let (a, b) = (a.borrow(), b.borrow());
+
+      if a.len() != b.len() || !a.keys().eq(b.keys()) {
+        return Ok(false);
+      }
+
+      Ok(all_equal(a.values().zip(b.values()), seen))
+    }
+
+    (Value::Record(a), Value::Record(b)) => {
+      let (a, b) = (a.borrow(), b.borrow());
+
+      if a.name != b.name {
+        return Ok(false);
+      }
+
+      Ok(all_equal(a.fields.iter().map(|(_, v)| v).zip(b.fields.iter().map(|(_, v)| v)), seen))
+    }
+
+    _ => Err(()),
+  }
+}
+
+// items of different types inside lists, maps and records are just different, not an error
+fn all_equal<'a>(mut pairs: impl Iterator<Item = (&'a Value, &'a Value)>, seen: &mut HashSet<(usize, usize)>) -> bool {
+  pairs.all(|(a, b)| equals_seen(a, b, seen).unwrap_or(false))
+}
@@ -1,67 +1,370 @@
-use std::{fs::File, io::{Error, Write}};
+use std::{collections::HashMap, fs::File, io::{Error, Write}};
 
-use crate::ast::{AstNode, AstNodeData};
+use crate::ast::{AstNode, AstNodeData, Type, Value, Var};
+
+// Opcodes past the last instruction, for `pushc` and `setc` with an operand that has a shorter
+// form than a full tagged value. The loader turns them back into the usual instructions.
+pub const PUSHC_FALSE: u8 = 91;
+pub const PUSHC_TRUE: u8 = 92;
+pub const PUSHC_NIL: u8 = 93;
+pub const PUSHC_INT: u8 = 94; // followed by the integer as a zigzag varint
+pub const SETC_FALSE: u8 = 95; // followed by the slot
+pub const SETC_TRUE: u8 = 96;
+pub const SETC_INT: u8 = 97; // followed by the integer as a zigzag varint, then the slot
+pub const PUSHC_SMALL: u8 = 128; // up to 255, for the integers from 0 to 127
 
 pub fn compile(ast: &[AstNode], path: &str) -> Result<(), Error> {
   let mut file = File::create(path)?;
-  let mut output: Vec<u8> = vec![];
+
+  file.write_all(&encode(ast))?;
+  Ok(())
+}
+
+/// Encodes a whole program: the string section, the symbol section, the constant section and
+/// the declaration section, followed by every instruction.
+pub fn encode(ast: &[AstNode]) -> Vec<u8> {
+  let mut pool = Pool::default();
+  let symbols: Vec<u32> = symbols(ast).iter().map(|name| pool.string(name)).collect();
+
+  // instructions add to the pool as they're encoded, and the pool must come first
+  let mut code: Vec<u8> = vec![];
+  let mut declarations: Vec<u32> = vec![]; // where each label, struct and decl of a struct starts in the code
 
   for node in ast {
-    let n = &node.data;
-    output.push(n.discriminant());
+    if let AstNodeData::Label(_) | AstNodeData::Struct(..) | AstNodeData::Decl(_, Type::Struct(_)) = node.data {
+      declarations.push(code.len() as u32);
+    }
+
+    encode_node(&mut code, &mut pool, &node.data);
+  }
+
+  let mut output: Vec<u8> = vec![];
+  encode_varint(&mut output, pool.strings.len() as u32);
+
+  for s in &pool.strings {
+    encode_string(&mut output, s);
+  }
+
+  encode_varint(&mut output, symbols.len() as u32);
+
+  for index in symbols {
+    encode_varint(&mut output, index);
+  }
+
+  encode_varint(&mut output, pool.constant_count);
+  output.extend_from_slice(&pool.constants);
+
+  encode_varint(&mut output, declarations.len() as u32);
+
+  for offset in declarations {
+    encode_varint(&mut output, offset);
+  }
+
+  output.extend_from_slice(&code);
+  output
+}
+
+/// The strings and constants of a program, each stored once and referred to by index.
+///
+/// Every name (variables, labels, structs and fields) and the contents of every string and
+/// label constant go in the string section. Every constant goes in the constant section,
+/// with the strings it holds written as indices into the string section.
+#[derive(Default)]
+pub struct Pool {
+  strings: Vec<String>,
+  string_indices: HashMap<String, u32>,
+  constants: Vec<u8>, // every constant, encoded one after another
+  constant_count: u32,
+  constant_indices: HashMap<Vec<u8>, u32>, // by encoding, which tells apart `0` and `-0` unlike `==`
+}
+
+impl Pool {
+  /// Finds the index of a string, adding it if it's not in the pool yet.
+  pub fn string(&mut self, s: &str) -> u32 {
+    if let Some(i) = self.string_indices.get(s) {
+      return *i;
+    }
+
+    let i = self.strings.len() as u32;
+    self.strings.push(s.to_string());
+    self.string_indices.insert(s.to_string(), i);
+    i
+  }
+
+  /// Finds the index of a constant, adding it if it's not in the pool yet.
+  pub fn constant(&mut self, value: &Value) -> u32 {
+    let mut encoded = vec![];
+    self.encode_value(&mut encoded, value);
+
+    if let Some(i) = self.constant_indices.get(&encoded) {
+      return *i;
+    }
+
+    let i = self.constant_count;
+    self.constant_count += 1;
+    self.constants.extend_from_slice(&encoded);
+    self.constant_indices.insert(encoded, i);
+    i
+  }
+
+  fn encode_value(&mut self, output: &mut Vec<u8>, value: &Value) {
+    output.push(value.discriminant());
+
+    match value {
+      Value::Num(n) => output.extend_from_slice(&n.to_le_bytes()),
+      Value::Str(s) | Value::Label(s) => encode_varint(output, self.string(s)),
+      Value::Bool(b) => output.push(*b as u8),
+      Value::List(list) => {
+        let items = list.borrow();
+        encode_varint(output, items.len() as u32);
+
+        for item in items.iter() {
+          self.encode_value(output, item);
+        }
+      }
+      Value::Map(map) => {
+        let entries = map.borrow();
+        encode_varint(output, entries.len() as u32);
+
+        for (key, value) in entries.iter() {
+          encode_varint(output, self.string(key));
+          self.encode_value(output, value);
+        }
+      }
+      Value::Ref(..) => unreachable!("references can't be written as constants"),
+      Value::Nil => {}, // discriminant already pushed
+      Value::Record(_) => unreachable!("records can't be written as constants"),
+    }
+  }
+
+  fn encode_type(&mut self, output: &mut Vec<u8>, ty: &Type) {
+    output.push(ty.discriminant());
+
+    if let Type::Struct(name) = ty {
+      encode_varint(output, self.string(name));
+    }
+  }
+}
+
+/// Encodes one instruction, with its opcode followed by its operands.
+pub fn encode_node(output: &mut Vec<u8>, pool: &mut Pool, n: &AstNodeData) {
+  match n {
+      AstNodeData::Pushc(Value::Bool(b)) => return output.push(if *b { PUSHC_TRUE } else { PUSHC_FALSE }),
+      AstNodeData::Pushc(Value::Nil) => return output.push(PUSHC_NIL),
 
-    match n {
-        AstNodeData::Pushc(val) => output.extend_from_slice(&val.encode()),
-        AstNodeData::Setc(var, val) => {
-          output.extend_from_slice(&val.encode());
-          encode_string(&mut output, var);
-        },
+      AstNodeData::Pushc(Value::Num(n)) => if let Some(i) = small_int(*n) {
+        if (0..128).contains(&i) {
+          return output.push(PUSHC_SMALL + i as u8);
+        }
 
-        AstNodeData::Add
-        | AstNodeData::Sub
-        | AstNodeData::Mul
-        | AstNodeData::Div
+        output.push(PUSHC_INT);
+        return encode_varint(output, zigzag(i));
+      }
 
-        | AstNodeData::Inc
-        | AstNodeData::Dec
+      AstNodeData::Setc(var, Value::Bool(b)) => {
+        output.push(if *b { SETC_TRUE } else { SETC_FALSE });
+        return encode_varint(output, var.slot);
+      }
+
+      AstNodeData::Setc(var, Value::Num(n)) => if let Some(i) = small_int(*n) {
+        output.push(SETC_INT);
+        encode_varint(output, zigzag(i));
+        return encode_varint(output, var.slot);
+      }
+
+      _ => {}
+  }
 
-        | AstNodeData::Pop
+  output.push(n.discriminant());
 
-        | AstNodeData::Inputn
-        | AstNodeData::Inputb
-        | AstNodeData::Inputs
+  match n {
+      AstNodeData::Pushc(val) => encode_varint(output, pool.constant(val)),
+      AstNodeData::Setc(var, val)
+      | AstNodeData::Const(var, val) => {
+        encode_varint(output, pool.constant(val));
+        encode_varint(output, var.slot);
+      },
 
-        | AstNodeData::Print
-        | AstNodeData::Println
+      AstNodeData::Add
+      | AstNodeData::Sub
+      | AstNodeData::Mul
+      | AstNodeData::Div
 
-        | AstNodeData::Cmpg
-        | AstNodeData::Cmpge
+      | AstNodeData::Inc
+      | AstNodeData::Dec
 
-        | AstNodeData::Cmpl
-        | AstNodeData::Cmple
+      | AstNodeData::Pop
 
-        | AstNodeData::Cmpe
-        | AstNodeData::Cmpne
-          
-        | AstNodeData::Jmp
-        | AstNodeData::Jt
-        | AstNodeData::Jf
+      | AstNodeData::Inputn
+      | AstNodeData::Inputb
+      | AstNodeData::Inputs
+
+      | AstNodeData::Print
+      | AstNodeData::Println
+
+      | AstNodeData::Cmpg
+      | AstNodeData::Cmpge
+
+      | AstNodeData::Cmpl
+      | AstNodeData::Cmple
+
+      | AstNodeData::Cmpe
+      | AstNodeData::Cmpne
         
-        | AstNodeData::Save
-        | AstNodeData::Ret => {}, // discriminant already pushed
+      | AstNodeData::Jmp
+      | AstNodeData::Jt
+      | AstNodeData::Jf
+      
+      | AstNodeData::Save
+      | AstNodeData::Ret
+
+      | AstNodeData::Mod
+      | AstNodeData::Pow
+      | AstNodeData::Neg
+      | AstNodeData::Abs
+      | AstNodeData::Min
+      | AstNodeData::Max
+
+      | AstNodeData::Floor
+      | AstNodeData::Ceil
+      | AstNodeData::Round
+      | AstNodeData::Sqrt
+
+      | AstNodeData::Land
+      | AstNodeData::Lor
+      | AstNodeData::Lnot
+      | AstNodeData::Lxor
+
+      | AstNodeData::Dup
+      | AstNodeData::Swap
+      | AstNodeData::Over
+      | AstNodeData::Rot
+
+      | AstNodeData::Strlen
+      | AstNodeData::Substr
+      | AstNodeData::Charat
+      | AstNodeData::Indexof
+      | AstNodeData::Split
+      | AstNodeData::Join
+      | AstNodeData::Upper
+      | AstNodeData::Lower
+      | AstNodeData::Trim
+      | AstNodeData::Replace
+      | AstNodeData::Startswith
+      | AstNodeData::Endswith
+
+      | AstNodeData::Tonum
+      | AstNodeData::Tostr
+      | AstNodeData::Tobool
+      | AstNodeData::Tolabel
+      | AstNodeData::Typeof
+
+      | AstNodeData::Listnew
+      | AstNodeData::Listpush
+      | AstNodeData::Listpop
+      | AstNodeData::Listget
+      | AstNodeData::Listset
+      | AstNodeData::Listlen
+      | AstNodeData::Listslice
+      | AstNodeData::Listunpack
+
+      | AstNodeData::Mapnew
+      | AstNodeData::Mapget
+      | AstNodeData::Mapset
+      | AstNodeData::Maphas
+      | AstNodeData::Mapdel
+      | AstNodeData::Mapkeys
+      | AstNodeData::Maplen
+
+      | AstNodeData::Load
+      | AstNodeData::Store
+
+      | AstNodeData::Copy
+
+      | AstNodeData::Isnil => {}, // discriminant already pushed
+
+      AstNodeData::Drop(n)
+      | AstNodeData::Pick(n) => encode_varint(output, *n),
 
-        AstNodeData::Pushv(var)
-        | AstNodeData::Popv(var) => encode_string(&mut output, var),
+      AstNodeData::Pushv(var)
+      | AstNodeData::Popv(var)
+      | AstNodeData::Ref(var)
+      | AstNodeData::Incv(var)
+      | AstNodeData::Decv(var) => encode_varint(output, var.slot),
 
-        AstNodeData::Label(label) => encode_string(&mut output, label),
+      AstNodeData::Jcmp(cmp, var, val, label) => {
+        output.push(*cmp as u8);
+        encode_varint(output, var.slot);
+        encode_varint(output, pool.constant(val));
+        encode_varint(output, pool.string(label));
+      }
+
+      AstNodeData::Struct(name, fields) => {
+        encode_varint(output, pool.string(name));
+        encode_varint(output, fields.len() as u32);
+
+        for (field, ty) in fields {
+          encode_varint(output, pool.string(field));
+          pool.encode_type(output, ty);
+        }
+      }
+
+      AstNodeData::Decl(var, ty) => {
+        encode_varint(output, var.slot);
+        pool.encode_type(output, ty);
+      }
+
+      AstNodeData::Label(name)
+      | AstNodeData::New(name)
+      | AstNodeData::Getf(name)
+      | AstNodeData::Setf(name) => encode_varint(output, pool.string(name)),
+  }
+}
+
+/// Lists the name of every variable slot, indexed by slot.
+pub fn symbols(ast: &[AstNode]) -> Vec<String> {
+  let mut symbols: Vec<String> = vec![];
+
+  for node in ast {
+    if let Some(Var { name, slot }) = node.data.var() {
+      let slot = *slot as usize;
+
+      if symbols.len() <= slot {
+        symbols.resize(slot + 1, String::new());
+      }
+
+      symbols[slot] = name.to_string();
     }
   }
 
-  file.write_all(&output)?;
-  Ok(())
+  symbols
 }
 
-pub fn encode_string(output: &mut Vec<u8>, s: &str) {
-  output.extend_from_slice(&(s.len() as u32).to_le_bytes());
+fn encode_string(output: &mut Vec<u8>, s: &str) {
+  encode_varint(output, s.len() as u32);
   output.extend_from_slice(s.as_bytes());
 }
+
+/// Writes an integer as LEB128: 7 bits per byte, least significant first, with the high bit
+/// set on every byte but the last.
+pub fn encode_varint(output: &mut Vec<u8>, mut n: u32) {
+  while n >= 0x80 {
+    output.push(n as u8 | 0x80);
+    n >>= 7;
+  }
+
+  output.push(n as u8);
+}
+
+/// The number as an integer, if converting it back gives the same number.
+fn small_int(n: f64) -> Option<i32> {
+  let i = n as i32;
+
+  // `-0.0` would come back as `0`
+  (i as f64 == n && !(n == 0.0 && n.is_sign_negative())).then_some(i)
+}
+
+/// Maps signed integers to unsigned ones so that small negative numbers stay small as varints.
+fn zigzag(n: i32) -> u32 {
+  ((n << 1) ^ (n >> 31)) as u32
+}
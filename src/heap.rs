@@ -0,0 +1,251 @@
+use std::{cell::RefCell, collections::{BTreeMap, HashSet}, mem::size_of, rc::{Rc, Weak}};
+
+use crate::ast::Value;
+
+pub type ListRef = Rc<RefCell<Vec<Value>>>;
+pub type MapRef = Rc<RefCell<BTreeMap<String, Value>>>;
+pub type RecordRef = Rc<RefCell<Record>>;
+
+/// Minimum number of allocations between two collections.
+const MIN_THRESHOLD: usize = 1024;
+
+/// An instance of a struct declared with `.struct`; fields are kept in declaration order.
+#[derive(Debug, Clone)]
+pub struct Record {
+  pub name: Rc<str>,
+  pub fields: Vec<(Rc<str>, Value)>,
+}
+
+impl Record {
+  pub fn get(&self, field: &str) -> Option<&Value> {
+    self.fields.iter().find(|(name, _)| **name == *field).map(|(_, v)| v)
+  }
+
+  pub fn get_mut(&mut self, field: &str) -> Option<&mut Value> {
+    self.fields.iter_mut().find(|(name, _)| **name == *field).map(|(_, v)| v)
+  }
+}
+
+enum Object {
+  List(Weak<RefCell<Vec<Value>>>),
+  Map(Weak<RefCell<BTreeMap<String, Value>>>),
+  Record(Weak<RefCell<Record>>),
+}
+
+impl Object {
+  fn upgrade(&self) -> Option<Value> {
+    match self {
+      Object::List(weak) => weak.upgrade().map(Value::List),
+      Object::Map(weak) => weak.upgrade().map(Value::Map),
+      Object::Record(weak) => weak.upgrade().map(Value::Record),
+    }
+  }
+
+  fn is_alive(&self) -> bool {
+    match self {
+      Object::List(weak) => weak.strong_count() > 0,
+      Object::Map(weak) => weak.strong_count() > 0,
+      Object::Record(weak) => weak.strong_count() > 0,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+  pub live_objects: usize,
+  pub bytes: usize,
+  pub collections: usize,
+}
+
+/// Keeps track of every list, map and record created while a program runs.
+///
+/// Objects are reference counted, so most of them are freed as soon as the last value
+/// pointing to them is dropped. Objects that point to each other are never freed that way,
+/// so from time to time `collect` finds the ones the program can't reach anymore and empties
+/// them, which breaks the cycles.
+pub struct Heap {
+  objects: Vec<Object>,
+  allocations: usize,
+  threshold: usize,
+  collections: usize,
+}
+
+impl Heap {
+  pub fn new() -> Self {
+    Self {
+      objects: vec![],
+      allocations: 0,
+      threshold: MIN_THRESHOLD,
+      collections: 0,
+    }
+  }
+
+  pub fn new_list(&mut self, items: Vec<Value>) -> Value {
+    let list = Rc::new(RefCell::new(items));
+
+    self.objects.push(Object::List(Rc::downgrade(&list)));
+    self.allocations += 1;
+
+    Value::List(list)
+  }
+
+  pub fn new_map(&mut self, entries: BTreeMap<String, Value>) -> Value {
+    let map = Rc::new(RefCell::new(entries));
+
+    self.objects.push(Object::Map(Rc::downgrade(&map)));
+    self.allocations += 1;
+
+    Value::Map(map)
+  }
+
+  pub fn new_record(&mut self, record: Record) -> Value {
+    let record = Rc::new(RefCell::new(record));
+
+    self.objects.push(Object::Record(Rc::downgrade(&record)));
+    self.allocations += 1;
+
+    Value::Record(record)
+  }
+
+  /// Makes a fresh copy of a constant, so that changing the lists and maps
+  /// it contains doesn't change the constant itself.
+  pub fn instantiate(&mut self, value: &Value) -> Value {
+    match value {
+      Value::List(list) => {
+        let items = list.borrow().iter().map(|v| self.instantiate(v)).collect();
+        self.new_list(items)
+      }
+
+      Value::Map(map) => {
+        let entries = map.borrow().iter().map(|(k, v)| (k.clone(), self.instantiate(v))).collect();
+        self.new_map(entries)
+      }
+
+      v => v.clone(),
+    }
+  }
+
+  /// Makes a shallow copy of a list, map or record; other values are returned as they are.
+  pub fn copy(&mut self, value: &Value) -> Value {
+    match value {
+      Value::List(list) => {
+        let items = list.borrow().clone();
+        self.new_list(items)
+      }
+
+      Value::Map(map) => {
+        let entries = map.borrow().clone();
+        self.new_map(entries)
+      }
+
+      Value::Record(record) => {
+        let record = record.borrow().clone();
+        self.new_record(record)
+      }
+
+      v => v.clone(),
+    }
+  }
+
+  pub fn should_collect(&self) -> bool {
+    self.allocations >= self.threshold
+  }
+
+  /// Frees every object that can't be reached from `roots`.
+  ///
+  /// Must only be called between instructions, when every value the program
+  /// can still use is reachable from the roots.
+  pub fn collect<'a>(&mut self, roots: impl Iterator<Item = &'a Value>) {
+    let mut marked: HashSet<usize> = HashSet::new();
+    let mut pending: Vec<Value> = roots.filter(|v| address(v).is_some()).cloned().collect();
+
+    while let Some(value) = pending.pop() {
+      if let Some(a) = address(&value) {
+        if marked.insert(a) {
+          pending.extend(children(&value));
+        }
+      }
+    }
+
+    // whatever is still alive but unmarked is only kept alive by other unreachable objects
+    for object in &self.objects {
+      if let Some(value) = object.upgrade() {
+        if !address(&value).is_some_and(|a| marked.contains(&a)) {
+          clear(&value);
+        }
+      }
+    }
+
+    self.objects.retain(Object::is_alive);
+
+    self.allocations = 0;
+    self.threshold = MIN_THRESHOLD.max(self.objects.len() * 2);
+    self.collections += 1;
+  }
+
+  /// Returns the current statistics. The byte count is an estimate of the memory
+  /// owned directly by the live objects, not counting strings they contain.
+  pub fn stats(&self) -> HeapStats {
+    let mut stats = HeapStats { collections: self.collections, ..Default::default() };
+
+    for value in self.objects.iter().filter_map(Object::upgrade) {
+      stats.live_objects += 1;
+      stats.bytes += size(&value);
+    }
+
+    stats
+  }
+}
+
+pub fn address(value: &Value) -> Option<usize> {
+  match value {
+    Value::List(list) => Some(Rc::as_ptr(list) as *const () as usize),
+    Value::Map(map) => Some(Rc::as_ptr(map) as *const () as usize),
+    Value::Record(record) => Some(Rc::as_ptr(record) as *const () as usize),
+    _ => None,
+  }
+}
+
+fn children(value: &Value) -> Vec<Value> {
+  let is_object = |v: &&Value| address(v).is_some();
+
+  match value {
+    Value::List(list) => list.borrow().iter().filter(is_object).cloned().collect(),
+    Value::Map(map) => map.borrow().values().filter(is_object).cloned().collect(),
+    Value::Record(record) => record.borrow().fields.iter().map(|(_, v)| v).filter(is_object).cloned().collect(),
+    _ => vec![],
+  }
+}
+
+fn clear(value: &Value) {
+  match value {
+    Value::List(list) => { std::mem::take(&mut *list.borrow_mut()); },
+    Value::Map(map) => { std::mem::take(&mut *map.borrow_mut()); },
+    Value::Record(record) => { std::mem::take(&mut record.borrow_mut().fields); },
+    _ => {}
+  }
+}
+
+fn size(value: &Value) -> usize {
+  match value {
+    Value::List(list) => size_of::<RefCell<Vec<Value>>>() + list.borrow().capacity() * size_of::<Value>(),
+
+    Value::Map(map) => {
+      let map = map.borrow();
+
+      size_of::<RefCell<BTreeMap<String, Value>>>()
+        + map.len() * (size_of::<String>() + size_of::<Value>())
+        + map.keys().map(|k| k.len()).sum::<usize>()
+    }
+
+    Value::Record(record) => {
+      let record = record.borrow();
+
+      size_of::<RefCell<Record>>() + record.name.len()
+        + record.fields.capacity() * size_of::<(Rc<str>, Value)>()
+        + record.fields.iter().map(|(k, _)| k.len()).sum::<usize>()
+    }
+
+    _ => 0,
+  }
+}
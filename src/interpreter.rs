@@ -1,13 +1,12 @@
-use std::{collections::HashMap, io::{self, Write}};
+use std::{borrow::Cow, collections::{BTreeMap, HashMap}, io::{self, Write}, rc::Rc};
 
-use crate::{ast::*, util::print_error_reduced};
+use crate::{ast::*, heap::{Heap, HeapStats, Record}, operators::{self, OperatorTable}, parser::Bytecode, scope::{Scopes, Variable}, util::{is_identifier, is_label, print_error_reduced}};
 
-type LabelMap = HashMap<String, usize>;
-type VariableMap = HashMap<String, Value>;
-type ScopeStack = Vec<VariableMap>;
+pub type LabelMap = HashMap<String, usize>; // to the position right after the label
+pub type StructMap = HashMap<String, StructDef>;
 
 macro_rules! try_pop {
-  ($operation_stack: expr, $inst: literal, $count: expr) => {
+  ($operation_stack: expr, $inst: expr, $count: expr) => {
     match $operation_stack.pop() {
       Some(v) => v,
       None => {
@@ -18,114 +17,208 @@ macro_rules! try_pop {
   };
 }
 
-pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
-  let labels = search_labels(ast);
+macro_rules! try_pop_as {
+  ($operation_stack: expr, $variant: ident, $inst: literal, $count: expr) => {
+    match try_pop!($operation_stack, $inst, $count) {
+      Value::$variant(v) => v,
+      v => {
+        print_error_reduced(&format!("In '{}' instruction: Expected a {} value, got {}", $inst, stringify!($variant).to_lowercase(), v.as_str_debug()), $count);
+        return Err(());
+      }
+    }
+  };
+}
+
+macro_rules! try_pop_index {
+  ($operation_stack: expr, $inst: literal, $count: expr) => {
+    match try_pop!($operation_stack, $inst, $count) {
+      Value::Num(n) if n >= 0.0 && n.fract() == 0.0 => n as usize,
+      v => {
+        print_error_reduced(&format!("In '{}' instruction: Expected an index (a non-negative integer), got {}", $inst, v.as_str_debug()), $count);
+        return Err(());
+      }
+    }
+  };
+}
+
+macro_rules! try_pop_ref {
+  ($operation_stack: expr, $inst: literal, $count: expr) => {
+    match try_pop!($operation_stack, $inst, $count) {
+      Value::Ref(var, id) => (var, id),
+      v => {
+        print_error_reduced(&format!("In '{}' instruction: Expected a ref value, got {}", $inst, v.as_str_debug()), $count);
+        return Err(());
+      }
+    }
+  };
+}
+
+/// A struct declared with `.struct`, with names its records share instead of copying.
+#[derive(Clone)]
+pub struct StructDef {
+  name: Rc<str>,
+  fields: Vec<(Rc<str>, Type)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+  /// Reading a variable that doesn't exist pushes `nil` instead of failing.
+  pub undefined_as_nil: bool,
+  /// Assigning to a variable that wasn't declared with `decl` or `const` is an error.
+  pub strict: bool,
+}
+
+/// The instructions the interpreter runs, each at a position that only the program knows the
+/// meaning of: an index into decoded instructions, or an offset into the bytecode.
+pub trait Program {
+  fn start(&self) -> usize;
+
+  /// The instruction at `pc` and the position of the next one, or `None` past the last one.
+  fn fetch(&self, pc: usize) -> Result<Option<(Cow<'_, AstNodeData<'_>>, usize)>, ()>;
+
+  /// Where every label and struct is, and how many variable slots there are, if the program
+  /// knows without going through all of its instructions.
+  fn declarations(&self) -> Option<(&[usize], usize)> {
+    None
+  }
+}
+
+impl Program for [ReducedAstNode<'_>] {
+  fn start(&self) -> usize {
+    0
+  }
+
+  fn fetch(&self, pc: usize) -> Result<Option<(Cow<'_, AstNodeData<'_>>, usize)>, ()> {
+    Ok(self.get(pc).map(|n| (Cow::Borrowed(&n.0), pc + 1)))
+  }
+}
+
+/// Runs straight from the bytecode, decoding every instruction as it's reached.
+impl Program for Bytecode<'_> {
+  fn start(&self) -> usize {
+    self.code
+  }
+
+  fn fetch(&self, pc: usize) -> Result<Option<(Cow<'_, AstNodeData<'_>>, usize)>, ()> {
+    if pc >= self.len() {
+      return Ok(None);
+    }
+
+    let mut next = pc;
+    let data = self.decode(&mut next)?;
+
+    Ok(Some((Cow::Owned(data), next)))
+  }
 
-  let mut operation_stack: Vec<Value> = vec![];
-  let mut variables: VariableMap = HashMap::new();
-  let mut scopes = vec![];
+  fn declarations(&self) -> Option<(&[usize], usize)> {
+    Some(Bytecode::declarations(self))
+  }
+}
+
+pub fn interpret<P: Program + ?Sized>(program: &P, options: &Options) -> Result<HeapStats, ()> {
+  let (labels, structs, slots) = scan(program)?;
+  let mut machine = Machine::new(labels, structs, slots, options);
+
+  let mut count = program.start();
   
-  let mut count: usize = 0;
+  while let Some((instruction, next)) = program.fetch(count)? {
+    machine.collect();
+    count = machine.execute(&instruction, count)?.unwrap_or(next);
+  }
   
-  while count < ast.len() {
-    match ast[count].0.clone() {
+  Ok(machine.heap.stats())
+}
+
+/// The state of a running program, and how each instruction changes it. The stack-based and
+/// the register-based interpreters both run instructions through it, so they behave the same.
+pub struct Machine<'o> {
+  pub heap: Heap,
+  pub operation_stack: Vec<Value>,
+  pub scopes: Scopes,
+  pub operators: OperatorTable,
+  labels: LabelMap,
+  structs: StructMap,
+  pub options: &'o Options,
+}
+
+impl<'o> Machine<'o> {
+  pub fn new(labels: LabelMap, structs: StructMap, slots: usize, options: &'o Options) -> Self {
+    Self {
+      heap: Heap::new(),
+      operation_stack: vec![],
+      scopes: Scopes::new(slots),
+      operators: OperatorTable::new(),
+      labels,
+      structs,
+      options,
+    }
+  }
+
+  /// Runs the collector if enough objects were created since it last ran.
+  pub fn collect(&mut self) {
+    if self.heap.should_collect() {
+      self.heap.collect(self.operation_stack.iter().chain(self.scopes.values()));
+    }
+  }
+
+  /// Runs one instruction, found at `count`, returning where it jumped to, if it did.
+  pub fn execute(&mut self, instruction: &AstNodeData, count: usize) -> Result<Option<usize>, ()> {
+    let Machine { heap, operation_stack, scopes, operators, labels, structs, options } = self;
+    let mut jump = None;
+
+    match instruction {
       AstNodeData::Label(_) => {},
       
-      AstNodeData::Pushc(value) => operation_stack.push(value),
-      
-      AstNodeData::Pushv(var) => match get_var(&variables, &scopes, &var) {
-        Some(value) => operation_stack.push(value.clone()),
-        None => print_error_reduced(&format!("In 'pushv' instruction: Variable '{}' doesn't exist", var), count),
-      },
-      
-      AstNodeData::Setc(var, value) => { variables.insert(var, value); }, // TODO | check if the variable wasn't present
-      AstNodeData::Popv(var) => {
-        variables.insert(var, match operation_stack.pop() {
-          Some(v) => v,
-          None => {
-            print_error_reduced("In 'popv' instruction: Attempt to pop the operation stack while being empty", count);
-            return Err(());
-          }
-        });
-      },
-      
-      AstNodeData::Pop => { try_pop!(operation_stack, "pop", count); },
+      AstNodeData::Pushc(value) => operation_stack.push(heap.instantiate(value)),
       
-      AstNodeData::Add => {
-        let a = try_pop!(operation_stack, "add", count);
-        let b = try_pop!(operation_stack, "add", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Num(a + b));
-
-            count += 1;
-            continue;
-          }
+      AstNodeData::Pushv(var) => match scopes.get(var.slot).map(|v| &v.value) {
+        Some(Some(value)) => operation_stack.push(value.clone()),
+        _ if options.undefined_as_nil => operation_stack.push(Value::Nil),
+        Some(None) => {
+          print_error_reduced(&format!("In 'pushv' instruction: Variable '{}' was declared but never assigned", var.name), count);
+          return Err(());
         }
-        
-        else if let Value::Str(a) = a.clone() {
-          if let Value::Str(b) = b {
-            operation_stack.push(Value::Str(format!("{}{}", a, b)));
-
-            count += 1;
-            continue;
-          }
+        None => {
+          print_error_reduced(&format!("In 'pushv' instruction: Variable '{}' doesn't exist", var.name), count);
+          return Err(());
         }
-        
-        print_error_reduced(&format!("In 'add' instruction: Cannot add {} and {}", a.as_str_debug(), b.as_str_debug()), count);
-        return Err(());
       },
-      AstNodeData::Sub => {
-        let a = try_pop!(operation_stack, "sub", count);
-        let b = try_pop!(operation_stack, "sub", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Num(a - b));
+      
+      AstNodeData::Setc(var, value) => {
+        let value = heap.instantiate(value);
 
-            count += 1;
-            continue;
-          }
+        if let Err(e) = scopes.assign(scopes.depth(), var, value, options.strict) {
+          print_error_reduced(&format!("In 'setc' instruction: {}", e), count);
+          return Err(());
         }
-        
-        print_error_reduced(&format!("In 'sub' instruction: Cannot subtract {} and {}", a.as_str_debug(), b.as_str_debug()), count);
-        return Err(());
       },
-      AstNodeData::Mul => {
-        let a = try_pop!(operation_stack, "mul", count);
-        let b = try_pop!(operation_stack, "mul", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Num(a * b));
-          }
-        }
-        
-        else {
-          print_error_reduced(&format!("In 'sub' instruction: Cannot multiply {} and {}", a.as_str_debug(), b.as_str_debug()), count);
+      AstNodeData::Popv(var) => {
+        let value = try_pop!(operation_stack, "popv", count);
+
+        if let Err(e) = scopes.assign(scopes.depth(), var, value, options.strict) {
+          print_error_reduced(&format!("In 'popv' instruction: {}", e), count);
           return Err(());
         }
       },
-      AstNodeData::Div => {
-        let a = try_pop!(operation_stack, "div", count);
-        let b = try_pop!(operation_stack, "div", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            if b == 0.0 {
-              print_error_reduced("In 'div' instruction: Cannot divide by zero", count);
-              return Err(());
-            }
-            
-            operation_stack.push(Value::Num(a / b));
+      
+      AstNodeData::Pop => { try_pop!(operation_stack, "pop", count); },
+      
+      AstNodeData::Add | AstNodeData::Sub | AstNodeData::Mul | AstNodeData::Div
+      | AstNodeData::Mod | AstNodeData::Pow | AstNodeData::Min | AstNodeData::Max
+      | AstNodeData::Cmpg | AstNodeData::Cmpge | AstNodeData::Cmpl | AstNodeData::Cmple | AstNodeData::Cmpe | AstNodeData::Cmpne
+      | AstNodeData::Land | AstNodeData::Lor | AstNodeData::Lxor => {
+        let inst = instruction.name();
+
+        let a = try_pop!(operation_stack, inst, count);
+        let b = try_pop!(operation_stack, inst, count);
+
+        match operators.apply(instruction.discriminant(), &a, &b) {
+          Ok(v) => operation_stack.push(v),
+          Err(e) => {
+            print_error_reduced(&format!("In '{}' instruction: {}", inst, e), count);
+            return Err(());
           }
         }
-        
-        else {
-          print_error_reduced(&format!("In 'div' instruction: Cannot divide {} and {}", a.as_str_debug(), b.as_str_debug()), count);
-          return Err(());
-        }
       },
 
       AstNodeData::Inc => {
@@ -154,6 +247,77 @@ pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
         }
       }
       
+      AstNodeData::Incv(var) | AstNodeData::Decv(var) => {
+        let inst = instruction.name();
+        let (step, verb) = if let AstNodeData::Incv(_) = instruction { (1.0, "increment") } else { (-1.0, "decrement") };
+
+        let n = match scopes.get(var.slot).map(|v| &v.value) {
+          Some(Some(Value::Num(n))) => *n,
+          Some(Some(x)) => {
+            print_error_reduced(&format!("In '{}' instruction: Cannot {} {}", inst, verb, x.as_str_debug()), count);
+            return Err(());
+          }
+          _ if options.undefined_as_nil => {
+            print_error_reduced(&format!("In '{}' instruction: Cannot {} nil", inst, verb), count);
+            return Err(());
+          }
+          Some(None) => {
+            print_error_reduced(&format!("In '{}' instruction: Variable '{}' was declared but never assigned", inst, var.name), count);
+            return Err(());
+          }
+          None => {
+            print_error_reduced(&format!("In '{}' instruction: Variable '{}' doesn't exist", inst, var.name), count);
+            return Err(());
+          }
+        };
+
+        let value = Value::Num(n + step);
+
+        if let Err(e) = scopes.assign(scopes.depth(), var, value, options.strict) {
+          print_error_reduced(&format!("In '{}' instruction: {}", inst, e), count);
+          return Err(());
+        }
+      }
+
+      AstNodeData::Jcmp(cmp, var, value, label) => {
+        let inst = instruction.name();
+        let nil = Value::Nil;
+
+        let a = match scopes.get(var.slot).map(|v| &v.value) {
+          Some(Some(a)) => a,
+          _ if options.undefined_as_nil => &nil,
+          Some(None) => {
+            print_error_reduced(&format!("In '{}' instruction: Variable '{}' was declared but never assigned", inst, var.name), count);
+            return Err(());
+          }
+          None => {
+            print_error_reduced(&format!("In '{}' instruction: Variable '{}' doesn't exist", inst, var.name), count);
+            return Err(());
+          }
+        };
+
+        // the constant is on top of the variable, as with `pushv` followed by `pushc`
+        let result = match operators.apply(*cmp as u8, value, a) {
+          Ok(v) => v,
+          Err(e) => {
+            print_error_reduced(&format!("In '{}' instruction: {}", inst, e), count);
+            return Err(());
+          }
+        };
+
+        let index = match labels.get(&**label) {
+          Some(i) => *i,
+          None => {
+            print_error_reduced(&format!("In '{}' instruction: Label {} doesn't exist", inst, label), count);
+            return Err(());
+          }
+        };
+
+        if let Value::Bool(true) = result {
+          jump = Some(index);
+        }
+      }
+
       AstNodeData::Inputn => {
         let mut s = String::new();
         input(&mut s);
@@ -187,128 +351,17 @@ pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
         let mut s = String::new();
         input(&mut s);
 
-        operation_stack.push(Value::Str(s));
+        operation_stack.push(Value::Str(s.into()));
       }
       
       AstNodeData::Print => print!("{}", try_pop!(operation_stack, "print", count).as_str()),
       AstNodeData::Println => println!("{}", try_pop!(operation_stack, "println", count).as_str()),
-      
-      AstNodeData::Cmpg => {
-        let a = try_pop!(operation_stack, "cmpg", count);
-        let b = try_pop!(operation_stack, "cmpg", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Bool(a > b));
-          }
-        }
-        
-        else {
-          print_error_reduced(&format!("In 'cmpg' instruction: Cannot compare {} and {} as greater", a.as_str_debug(), b.as_str_debug()), count);
-          return Err(());
-        }
-      }
-      AstNodeData::Cmpge => {
-        let a = try_pop!(operation_stack, "cmpge", count);
-        let b = try_pop!(operation_stack, "cmpge", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Bool(a >= b));
-          }
-        }
-        
-        else {
-          print_error_reduced(&format!("In 'cmpg' instruction: Cannot compare {} and {} as greater or equal", a.as_str_debug(), b.as_str_debug()), count);
-          return Err(());
-        }
-      }
-      
-      AstNodeData::Cmpl => {
-        let a = try_pop!(operation_stack, "cmpl", count);
-        let b = try_pop!(operation_stack, "cmpl", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Bool(a < b));
-          }
-        }
-        
-        else {
-          print_error_reduced(&format!("In 'cmpg' instruction: Cannot compare {} and {} as less", a.as_str_debug(), b.as_str_debug()), count);
-          return Err(());
-        }
-      }
-      AstNodeData::Cmple => {
-        let a = try_pop!(operation_stack, "cmple", count);
-        let b = try_pop!(operation_stack, "cmple", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Bool(a <= b));
-          }
-        }
-        
-        else {
-          print_error_reduced(&format!("In 'cmpg' instruction: Cannot compare {} and {} as less or equal", a.as_str_debug(), b.as_str_debug()), count);
-          return Err(());
-        }
-      },
-      
-      AstNodeData::Cmpe => {
-        let a = try_pop!(operation_stack, "cmpe", count);
-        let b = try_pop!(operation_stack, "cmpe", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Bool(a == b));
-          }
-        }
-
-        else if let Value::Str(a) = a.clone() {
-          if let Value::Str(b) = b {
-            operation_stack.push(Value::Bool(a == b));
 
-            count += 1;
-            continue;
-          }
-        }
-        
-        else {
-          print_error_reduced(&format!("In 'cmpe' instruction: Cannot compare {} and {} as equal (they must be of the same type)", a.as_str_debug(), b.as_str_debug()), count);
-          return Err(());
-        }
-      },
-      AstNodeData::Cmpne => {
-        let a = try_pop!(operation_stack, "cmpne", count);
-        let b = try_pop!(operation_stack, "cmpne", count);
-        
-        if let Value::Num(a) = a {
-          if let Value::Num(b) = b {
-            operation_stack.push(Value::Bool(a != b));
-          }
-        }
-
-        else if let Value::Str(a) = a.clone() {
-          if let Value::Str(b) = b {
-            operation_stack.push(Value::Bool(a != b));
-
-            count += 1;
-            continue;
-          }
-        }
-        
-        else {
-          print_error_reduced(&format!("In 'cmpe' instruction: Cannot compare {} and {} as not equal (they must be of the same type)", a.as_str_debug(), b.as_str_debug()), count);
-          return Err(());
-        }
-      },
-      
       AstNodeData::Jmp => {
         let label = try_pop!(operation_stack, "jmp", count);
 
         if let Value::Label(l) = label {
-          let index = match labels.get(&l) {
+          let index = match labels.get(&*l) {
             Some(i) => *i,
             None => {
               print_error_reduced(&format!("In 'jmp' instruction: Label {} doesn't exist", l), count);
@@ -316,7 +369,7 @@ pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
             }
           };
 
-          count = index;
+          jump = Some(index);
         }
         else {
           print_error_reduced(&format!("In 'jmp' instruction: Cannot jump to {}; must be a label", label.as_str_debug()), count);
@@ -327,7 +380,7 @@ pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
         let label = try_pop!(operation_stack, "jt", count);
 
         if let Value::Label(ref l) = label {
-          let index = match labels.get(l) {
+          let index = match labels.get(&**l) {
             Some(i) => *i,
             None => {
               print_error_reduced(&format!("In 'jt' instruction: Label {} doesn't exist", label.as_str_debug()), count);
@@ -339,7 +392,7 @@ pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
 
           if let Value::Bool(b) = v {
             if b {
-              count = index;
+              jump = Some(index);
             }
           }
 
@@ -357,7 +410,7 @@ pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
         let label = try_pop!(operation_stack, "jf", count);
 
         if let Value::Label(ref l) = label {
-          let index = match labels.get(l) {
+          let index = match labels.get(&**l) {
             Some(i) => *i,
             None => {
               print_error_reduced(&format!("In 'jf' instruction: Label {} doesn't exist", label.as_str_debug()), count);
@@ -369,7 +422,7 @@ pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
 
           if let Value::Bool(b) = v {
             if !b {
-              count = index;
+              jump = Some(index);
             }
           }
 
@@ -384,52 +437,619 @@ pub fn interpret(ast: &[ReducedAstNode]) -> Result<(), ()> {
         }
       }
 
-      AstNodeData::Save => {
-        scopes.push(variables.clone());
-        variables = HashMap::new();
-      }
+      AstNodeData::Save => scopes.enter(),
 
       AstNodeData::Ret => {
-        variables = match scopes.pop() {
+        if scopes.exit().is_err() {
+          print_error_reduced("In 'ret' instruction: Attempt to pop the scope stack while being empty", count);
+          return Err(());
+        }
+      }
+
+      AstNodeData::Neg
+      | AstNodeData::Abs
+      | AstNodeData::Floor
+      | AstNodeData::Ceil
+      | AstNodeData::Round
+      | AstNodeData::Sqrt
+      | AstNodeData::Lnot => {
+        let inst = instruction.name();
+        let x = try_pop!(operation_stack, inst, count);
+
+        match operators::unary(instruction, &x) {
+          Ok(v) => operation_stack.push(v),
+          Err(e) => {
+            print_error_reduced(&format!("In '{}' instruction: {}", inst, e), count);
+            return Err(());
+          }
+        }
+      }
+
+      AstNodeData::Dup => {
+        let x = try_pop!(operation_stack, "dup", count);
+
+        operation_stack.push(x.clone());
+        operation_stack.push(x);
+      }
+      AstNodeData::Swap => {
+        let a = try_pop!(operation_stack, "swap", count);
+        let b = try_pop!(operation_stack, "swap", count);
+
+        operation_stack.push(a);
+        operation_stack.push(b);
+      }
+      AstNodeData::Over => {
+        let a = try_pop!(operation_stack, "over", count);
+        let b = try_pop!(operation_stack, "over", count);
+
+        operation_stack.push(b.clone());
+        operation_stack.push(a);
+        operation_stack.push(b);
+      }
+      AstNodeData::Rot => {
+        let a = try_pop!(operation_stack, "rot", count);
+        let b = try_pop!(operation_stack, "rot", count);
+        let c = try_pop!(operation_stack, "rot", count);
+
+        operation_stack.push(b);
+        operation_stack.push(a);
+        operation_stack.push(c);
+      }
+      AstNodeData::Drop(n) => {
+        let n = *n as usize;
+
+        if n > operation_stack.len() {
+          print_error_reduced(&format!("In 'drop' instruction: Attempt to drop {} items from the operation stack, but it only has {}", n, operation_stack.len()), count);
+          return Err(());
+        }
+
+        operation_stack.truncate(operation_stack.len() - n);
+      }
+      AstNodeData::Pick(n) => {
+        let n = *n as usize;
+
+        if n >= operation_stack.len() {
+          print_error_reduced(&format!("In 'pick' instruction: Attempt to pick item {} from the operation stack, but it only has {}", n, operation_stack.len()), count);
+          return Err(());
+        }
+
+        operation_stack.push(operation_stack[operation_stack.len() - 1 - n].clone());
+      }
+
+      AstNodeData::Strlen => {
+        let s = try_pop_as!(operation_stack, Str, "strlen", count);
+        operation_stack.push(Value::Num(s.chars().count() as f64));
+      }
+      AstNodeData::Substr => {
+        let s = try_pop_as!(operation_stack, Str, "substr", count);
+        let start = try_pop_index!(operation_stack, "substr", count);
+        let len = try_pop_index!(operation_stack, "substr", count);
+
+        let char_count = s.chars().count();
+
+        if start > char_count || len > char_count - start {
+          print_error_reduced(&format!("In 'substr' instruction: Range {}..{} is out of bounds for a string of length {}", start, start.saturating_add(len), char_count), count);
+          return Err(());
+        }
+
+        operation_stack.push(Value::Str(s.chars().skip(start).take(len).collect::<String>().into()));
+      }
+      AstNodeData::Charat => {
+        let s = try_pop_as!(operation_stack, Str, "charat", count);
+        let index = try_pop_index!(operation_stack, "charat", count);
+
+        match s.chars().nth(index) {
+          Some(c) => operation_stack.push(Value::Str(c.to_string().into())),
+          None => {
+            print_error_reduced(&format!("In 'charat' instruction: Index {} is out of bounds for a string of length {}", index, s.chars().count()), count);
+            return Err(());
+          }
+        }
+      }
+      AstNodeData::Indexof => {
+        let s = try_pop_as!(operation_stack, Str, "indexof", count);
+        let needle = try_pop_as!(operation_stack, Str, "indexof", count);
+
+        let index = match s.find(&*needle) {
+          Some(byte_index) => s[..byte_index].chars().count() as f64,
+          None => -1.0,
+        };
+
+        operation_stack.push(Value::Num(index));
+      }
+      AstNodeData::Split => {
+        let s = try_pop_as!(operation_stack, Str, "split", count);
+        let separator = try_pop_as!(operation_stack, Str, "split", count);
+
+        let parts: Vec<Rc<str>> = if separator.is_empty() {
+          s.chars().map(|c| c.to_string().into()).collect()
+        }
+        else {
+          s.split(&*separator).map(Rc::from).collect()
+        };
+
+        let len = parts.len();
+        operation_stack.extend(parts.into_iter().map(Value::Str));
+        operation_stack.push(Value::Num(len as f64));
+      }
+      AstNodeData::Join => {
+        let separator = try_pop_as!(operation_stack, Str, "join", count);
+        let len = try_pop_index!(operation_stack, "join", count);
+
+        if len > operation_stack.len() {
+          print_error_reduced(&format!("In 'join' instruction: Attempt to join {} strings, but the operation stack only has {} items", len, operation_stack.len()), count);
+          return Err(());
+        }
+
+        let mut parts = Vec::with_capacity(len);
+
+        for v in operation_stack.drain(operation_stack.len() - len..) {
+          match v {
+            Value::Str(s) => parts.push(s),
+            v => {
+              print_error_reduced(&format!("In 'join' instruction: Expected a str value, got {}", v.as_str_debug()), count);
+              return Err(());
+            }
+          }
+        }
+
+        operation_stack.push(Value::Str(parts.join(&*separator).into()));
+      }
+      AstNodeData::Upper => {
+        let s = try_pop_as!(operation_stack, Str, "upper", count);
+        operation_stack.push(Value::Str(s.to_uppercase().into()));
+      }
+      AstNodeData::Lower => {
+        let s = try_pop_as!(operation_stack, Str, "lower", count);
+        operation_stack.push(Value::Str(s.to_lowercase().into()));
+      }
+      AstNodeData::Trim => {
+        let s = try_pop_as!(operation_stack, Str, "trim", count);
+        operation_stack.push(Value::Str(s.trim().into()));
+      }
+      AstNodeData::Replace => {
+        let s = try_pop_as!(operation_stack, Str, "replace", count);
+        let from = try_pop_as!(operation_stack, Str, "replace", count);
+        let to = try_pop_as!(operation_stack, Str, "replace", count);
+
+        if from.is_empty() {
+          print_error_reduced("In 'replace' instruction: Cannot replace an empty string", count);
+          return Err(());
+        }
+
+        operation_stack.push(Value::Str(s.replace(&*from, &to).into()));
+      }
+      AstNodeData::Startswith => {
+        let s = try_pop_as!(operation_stack, Str, "startswith", count);
+        let prefix = try_pop_as!(operation_stack, Str, "startswith", count);
+
+        operation_stack.push(Value::Bool(s.starts_with(&*prefix)));
+      }
+      AstNodeData::Endswith => {
+        let s = try_pop_as!(operation_stack, Str, "endswith", count);
+        let suffix = try_pop_as!(operation_stack, Str, "endswith", count);
+
+        operation_stack.push(Value::Bool(s.ends_with(&*suffix)));
+      }
+
+      AstNodeData::Tonum => {
+        let x = try_pop!(operation_stack, "tonum", count);
+
+        let converted = match &x {
+          Value::Num(n) => Some(*n),
+          Value::Str(s) => s.trim().parse::<f64>().ok(),
+          Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+          Value::Label(_) | Value::List(_) | Value::Map(_) | Value::Ref(..) | Value::Nil | Value::Record(_) => None,
+        };
+
+        push_conversion(operation_stack, x, converted.map(Value::Num));
+      }
+      AstNodeData::Tostr => {
+        let x = try_pop!(operation_stack, "tostr", count);
+        operation_stack.push(Value::Str(x.as_str().into()));
+      }
+      AstNodeData::Tobool => {
+        let x = try_pop!(operation_stack, "tobool", count);
+
+        let converted = match &x {
+          Value::Num(n) if n.is_nan() => None,
+          Value::Num(n) => Some(*n != 0.0),
+          Value::Str(s) if &**s == "true" || &**s == "false" => Some(&**s == "true"),
+          Value::Str(_) => None,
+          Value::Bool(b) => Some(*b),
+          Value::Label(_) | Value::List(_) | Value::Map(_) | Value::Ref(..) | Value::Nil | Value::Record(_) => None,
+        };
+
+        push_conversion(operation_stack, x, converted.map(Value::Bool));
+      }
+      AstNodeData::Tolabel => {
+        let x = try_pop!(operation_stack, "tolabel", count);
+
+        let converted = match &x {
+          Value::Label(l) => Some(l.clone()),
+          Value::Str(s) if is_label(s) => Some(s.clone()),
+          Value::Str(s) if !s.is_empty() && is_identifier(s) => Some(format!("#{}", s).into()),
+          _ => None,
+        };
+
+        push_conversion(operation_stack, x, converted.map(Value::Label));
+      }
+      AstNodeData::Typeof => {
+        let x = try_pop!(operation_stack, "typeof", count);
+
+        match x {
+          Value::Record(record) => operation_stack.push(Value::Str(record.borrow().name.clone())),
+          x => operation_stack.push(Value::Str(x.type_name().into())),
+        }
+      }
+
+      AstNodeData::Listnew => operation_stack.push(heap.new_list(vec![])),
+      AstNodeData::Listpush => {
+        let list = try_pop_as!(operation_stack, List, "listpush", count);
+        let x = try_pop!(operation_stack, "listpush", count);
+
+        list.borrow_mut().push(x);
+        operation_stack.push(Value::List(list));
+      }
+      AstNodeData::Listpop => {
+        let list = try_pop_as!(operation_stack, List, "listpop", count);
+        let popped = list.borrow_mut().pop();
+
+        match popped {
+          Some(x) => {
+            operation_stack.push(Value::List(list));
+            operation_stack.push(x);
+          }
+          None => {
+            print_error_reduced("In 'listpop' instruction: Attempt to pop from an empty list", count);
+            return Err(());
+          }
+        }
+      }
+      AstNodeData::Listget => {
+        let list = try_pop_as!(operation_stack, List, "listget", count);
+        let index = try_pop_index!(operation_stack, "listget", count);
+
+        let list = list.borrow();
+
+        match list.get(index) {
+          Some(x) => operation_stack.push(x.clone()),
+          None => {
+            print_error_reduced(&format!("In 'listget' instruction: Index {} is out of bounds for a list of length {}", index, list.len()), count);
+            return Err(());
+          }
+        }
+      }
+      AstNodeData::Listset => {
+        let list = try_pop_as!(operation_stack, List, "listset", count);
+        let index = try_pop_index!(operation_stack, "listset", count);
+        let x = try_pop!(operation_stack, "listset", count);
+
+        let len = list.borrow().len();
+
+        if index >= len {
+          print_error_reduced(&format!("In 'listset' instruction: Index {} is out of bounds for a list of length {}", index, len), count);
+          return Err(());
+        }
+
+        list.borrow_mut()[index] = x;
+        operation_stack.push(Value::List(list));
+      }
+      AstNodeData::Listlen => {
+        let list = try_pop_as!(operation_stack, List, "listlen", count);
+        operation_stack.push(Value::Num(list.borrow().len() as f64));
+      }
+      AstNodeData::Listslice => {
+        let list = try_pop_as!(operation_stack, List, "listslice", count);
+        let start = try_pop_index!(operation_stack, "listslice", count);
+        let len = try_pop_index!(operation_stack, "listslice", count);
+
+        let items = list.borrow();
+
+        if start > items.len() || len > items.len() - start {
+          print_error_reduced(&format!("In 'listslice' instruction: Range {}..{} is out of bounds for a list of length {}", start, start.saturating_add(len), items.len()), count);
+          return Err(());
+        }
+
+        let slice = items[start..start + len].to_vec();
+        drop(items);
+
+        operation_stack.push(heap.new_list(slice));
+      }
+      AstNodeData::Listunpack => {
+        let list = try_pop_as!(operation_stack, List, "listunpack", count);
+        let len = list.borrow().len();
+
+        operation_stack.extend(list.borrow().iter().cloned());
+        operation_stack.push(Value::Num(len as f64));
+      }
+
+      AstNodeData::Mapnew => operation_stack.push(heap.new_map(BTreeMap::new())),
+      AstNodeData::Mapget => {
+        let map = try_pop_as!(operation_stack, Map, "mapget", count);
+        let key = try_pop_as!(operation_stack, Str, "mapget", count);
+
+        let map = map.borrow();
+
+        match map.get(&*key) {
+          Some(x) => operation_stack.push(x.clone()),
+          None => {
+            print_error_reduced(&format!("In 'mapget' instruction: Key \"{}\" doesn't exist", key), count);
+            return Err(());
+          }
+        }
+      }
+      AstNodeData::Mapset => {
+        let map = try_pop_as!(operation_stack, Map, "mapset", count);
+        let key = try_pop_as!(operation_stack, Str, "mapset", count);
+        let x = try_pop!(operation_stack, "mapset", count);
+
+        map.borrow_mut().insert(key.to_string(), x);
+        operation_stack.push(Value::Map(map));
+      }
+      AstNodeData::Maphas => {
+        let map = try_pop_as!(operation_stack, Map, "maphas", count);
+        let key = try_pop_as!(operation_stack, Str, "maphas", count);
+
+        operation_stack.push(Value::Bool(map.borrow().contains_key(&*key)));
+      }
+      AstNodeData::Mapdel => {
+        let map = try_pop_as!(operation_stack, Map, "mapdel", count);
+        let key = try_pop_as!(operation_stack, Str, "mapdel", count);
+
+        map.borrow_mut().remove(&*key);
+        operation_stack.push(Value::Map(map));
+      }
+      AstNodeData::Mapkeys => {
+        let map = try_pop_as!(operation_stack, Map, "mapkeys", count);
+        let keys = map.borrow().keys().map(|k| Value::Str(k.as_str().into())).collect();
+
+        operation_stack.push(heap.new_list(keys));
+      }
+      AstNodeData::Maplen => {
+        let map = try_pop_as!(operation_stack, Map, "maplen", count);
+        operation_stack.push(Value::Num(map.borrow().len() as f64));
+      }
+
+      AstNodeData::Isnil => {
+        let x = try_pop!(operation_stack, "isnil", count);
+        operation_stack.push(Value::Bool(matches!(x, Value::Nil)));
+      }
+
+      AstNodeData::Decl(var, ty) => {
+        if let Err(e) = scopes.declare(var, Variable { value: None, ty: Some(ty.clone()), constant: false }) {
+          print_error_reduced(&format!("In 'decl' instruction: {}", e), count);
+          return Err(());
+        }
+      }
+      AstNodeData::Const(var, value) => {
+        let value = heap.instantiate(value);
+
+        if let Err(e) = scopes.declare(var, Variable { value: Some(value), ty: Some(Type::Any), constant: true }) {
+          print_error_reduced(&format!("In 'const' instruction: {}", e), count);
+          return Err(());
+        }
+      }
+
+      AstNodeData::Struct(..) => {}, // structs are collected before running
+      AstNodeData::New(name) => {
+        let declared = match structs.get(name.as_ref()) {
           Some(s) => s,
           None => {
-            print_error_reduced("In 'ret' instruction: Attempt to pop the scope stack while being empty", count);
+            print_error_reduced(&format!("In 'new' instruction: Struct '{}' doesn't exist", name), count);
+            return Err(());
+          }
+        };
+
+        let mut fields = Vec::with_capacity(declared.fields.len());
+
+        for (field, ty) in &declared.fields {
+          let x = try_pop!(operation_stack, "new", count);
+
+          if !ty.matches(&x) {
+            print_error_reduced(&format!("In 'new' instruction: Field '{}' of struct '{}' must be of type {}, got {}", field, name, ty.name(), x.as_str_debug()), count);
+            return Err(());
+          }
+
+          fields.push((field.clone(), x));
+        }
+
+        operation_stack.push(heap.new_record(Record { name: declared.name.clone(), fields }));
+      }
+      AstNodeData::Getf(field) => {
+        let record = try_pop_as!(operation_stack, Record, "getf", count);
+        let record = record.borrow();
+
+        match record.get(field) {
+          Some(x) => operation_stack.push(x.clone()),
+          None => {
+            print_error_reduced(&format!("In 'getf' instruction: Struct '{}' has no field '{}'", record.name, field), count);
+            return Err(());
+          }
+        }
+      }
+      AstNodeData::Setf(field) => {
+        let record = try_pop_as!(operation_stack, Record, "setf", count);
+        let x = try_pop!(operation_stack, "setf", count);
+
+        {
+          let mut r = record.borrow_mut();
+          let name = r.name.clone();
+
+          // every record was built by 'new', so its struct is always declared
+          let ty = structs[&*name].fields.iter().find(|(f, _)| **f == **field);
+
+          let slot = match (ty, r.get_mut(field)) {
+            (Some((_, ty)), Some(slot)) if ty.matches(&x) => slot,
+            (Some((_, ty)), Some(_)) => {
+              print_error_reduced(&format!("In 'setf' instruction: Field '{}' of struct '{}' must be of type {}, got {}", field, name, ty.name(), x.as_str_debug()), count);
+              return Err(());
+            }
+            _ => {
+              print_error_reduced(&format!("In 'setf' instruction: Struct '{}' has no field '{}'", name, field), count);
+              return Err(());
+            }
+          };
+
+          *slot = x;
+        }
+
+        operation_stack.push(Value::Record(record));
+      }
+
+      AstNodeData::Copy => {
+        let x = try_pop!(operation_stack, "copy", count);
+        operation_stack.push(heap.copy(&x));
+      }
+
+      AstNodeData::Ref(var) => {
+        let id = scopes.find(var.slot);
+        operation_stack.push(Value::Ref(var.clone(), id));
+      }
+      AstNodeData::Load => {
+        let (var, id) = try_pop_ref!(operation_stack, "load", count);
+
+        let depth = match scopes.resolve(id) {
+          Some(d) => d,
+          None => {
+            print_error_reduced(&format!("In 'load' instruction: Reference to variable '{}' is dangling (its scope was already popped by 'ret')", var.name), count);
+            return Err(());
+          }
+        };
+
+        match scopes.get_at(depth, var.slot).map(|v| &v.value) {
+          Some(Some(value)) => operation_stack.push(value.clone()),
+          _ if options.undefined_as_nil => operation_stack.push(Value::Nil),
+          Some(None) => {
+            print_error_reduced(&format!("In 'load' instruction: Variable '{}' was declared but never assigned", var.name), count);
+            return Err(());
+          }
+          None => {
+            print_error_reduced(&format!("In 'load' instruction: Variable '{}' doesn't exist", var.name), count);
+            return Err(());
+          }
+        }
+      }
+      AstNodeData::Store => {
+        let (var, id) = try_pop_ref!(operation_stack, "store", count);
+        let x = try_pop!(operation_stack, "store", count);
+
+        match scopes.resolve(id) {
+          Some(depth) => if let Err(e) = scopes.assign(depth, &var, x, options.strict) {
+            print_error_reduced(&format!("In 'store' instruction: {}", e), count);
+            return Err(());
+          },
+          None => {
+            print_error_reduced(&format!("In 'store' instruction: Reference to variable '{}' is dangling (its scope was already popped by 'ret')", var.name), count);
             return Err(());
           }
         }
       }
     }
-    
-    count += 1;
+
+    Ok(jump)
   }
-  
-  Ok(())
 }
 
-fn search_labels(ast: &[ReducedAstNode]) -> LabelMap {
-  let mut map = HashMap::new();
-  
-  for (i, n) in ast.iter().enumerate() {
-    if let AstNodeData::Label(name) = n.0.clone() {
-      map.insert(name, i);
+/// Goes through the program once before running it, collecting its labels and structs,
+/// and counting the variable slots it uses, which is the size of every scope.
+pub fn scan<P: Program + ?Sized>(program: &P) -> Result<(LabelMap, StructMap, usize), ()> {
+  let mut labels = HashMap::new();
+  let mut structs = HashMap::new();
+  let mut declared: Vec<(usize, String)> = vec![];
+  let mut decls: Vec<(usize, String, String)> = vec![]; // where each decl of a struct is, its variable and struct
+  let mut slots = 0;
+
+  // only the labels, structs and decls of a struct need to be gone through, when the program knows where they are
+  let mut positions = program.declarations().map(|(positions, symbols)| {
+    slots = symbols;
+    positions.iter().copied()
+  });
+
+  let mut count = program.start();
+
+  loop {
+    if let Some(positions) = &mut positions {
+      match positions.next() {
+        Some(position) => count = position,
+        None => break,
+      }
+    }
+
+    let Some((n, next)) = program.fetch(count)? else { break };
+
+    match &*n {
+      AstNodeData::Label(name) => { labels.insert(name.to_string(), next); }
+
+      AstNodeData::Struct(name, fields) => {
+        let def = StructDef {
+          name: name.as_ref().into(),
+          fields: fields.iter().map(|(f, ty)| (f.as_ref().into(), ty.clone())).collect(),
+        };
+
+        if structs.insert(name.to_string(), def).is_some() {
+          print_error_reduced(&format!("Struct '{}' is declared more than once", name), count);
+          return Err(());
+        }
+
+        declared.push((count, name.to_string()));
+      }
+
+      AstNodeData::Decl(var, ty) => {
+        if let Type::Struct(s) = ty {
+          decls.push((count, var.name.to_string(), s.clone()));
+        }
+
+        if positions.is_none() {
+          slots = slots.max(var.slot as usize + 1);
+        }
+      }
+
+      n if positions.is_some() => {
+        print_error_reduced(&format!("The declaration section points to a '{}' instruction, instead of a label, a struct or a decl", n.name()), count);
+        return Err(());
+      }
+
+      n => if let Some(var) = n.var() {
+        slots = slots.max(var.slot as usize + 1);
+      }
     }
+
+    count = next;
   }
-  
-  map
-}
 
-fn get_var(scope: &VariableMap, stack: &ScopeStack, name: &str) -> Option<Value> {
-  match scope.get(name) {
-    Some(v) => Some(v.clone()),
-    None => {
-      for scope in stack.iter().rev() {
-        match scope.get(name) {
-          Some(v) => return Some(v.clone()),
-          None => continue
+  for (i, name) in &declared {
+    for (field, ty) in &structs[name].fields {
+      if let Type::Struct(s) = ty {
+        if !structs.contains_key(s) {
+          print_error_reduced(&format!("Type '{}' of field '{}' in struct '{}' doesn't exist", s, field, name), *i);
+          return Err(());
         }
       }
+    }
+  }
 
-      None
+  for (i, var, s) in &decls {
+    if !structs.contains_key(s) {
+      print_error_reduced(&format!("Type '{}' of variable '{}' doesn't exist", s, var), *i);
+      return Err(());
+    }
+  }
+
+  Ok((labels, structs, slots))
+}
+
+/// Pushes the result of a conversion instruction: the converted value and `true` on success,
+/// or the original value and `false` on failure, so the stack has the same shape either way.
+fn push_conversion(operation_stack: &mut Vec<Value>, original: Value, converted: Option<Value>) {
+  match converted {
+    Some(v) => {
+      operation_stack.push(v);
+      operation_stack.push(Value::Bool(true));
+    }
+    None => {
+      operation_stack.push(original);
+      operation_stack.push(Value::Bool(false));
     }
   }
 }
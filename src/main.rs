@@ -1,18 +1,47 @@
-use std::{env, fs, process::exit};
+use std::{env, fs::{self, File}, process::exit};
+
+use memmap2::Mmap;
 
 mod ast;
 mod parser;
 mod compiler;
+mod checker;
+mod optimizer;
+mod register;
+mod stats;
+mod compare;
+mod operators;
 mod interpreter;
+mod heap;
+#[cfg(feature = "alloc-stats")]
+mod allocator;
+mod scope;
 mod util;
 
 const FILE_EXTENSION: &str = "mch";
+const FLAGS: &[&str] = &["-O", "--heap-stats", "--alloc-stats", "--undefined-as-nil", "--strict", "--stream", "--emit-optimized-asm", "--expand", "--registers"];
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: allocator::CountingAllocator = allocator::CountingAllocator;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let (args, flags): (Vec<String>, Vec<String>) = env::args().partition(|a| !a.starts_with('-'));
     
     if args.len() != 3 {
-        eprintln!("Usage: machina assemble/run <file>");
+        eprintln!("Usage: machina assemble/run/check/stats/disassemble [-O] [--heap-stats] [--alloc-stats] [--undefined-as-nil] [--strict] [--stream] [--emit-optimized-asm] [--expand] [--registers] <file>");
+        exit(1);
+    }
+
+    for flag in &flags {
+        if !FLAGS.contains(&flag.as_str()) {
+            eprintln!("Invalid flag: '{flag}'. Available flags: {}.", FLAGS.iter().map(|f| format!("'{f}'")).collect::<Vec<_>>().join(", "));
+            exit(1);
+        }
+    }
+
+    if cfg!(not(feature = "alloc-stats")) && flags.iter().any(|f| f == "--alloc-stats") {
+        eprintln!("'--alloc-stats' needs Machina to be built with the 'alloc-stats' feature: cargo build --features alloc-stats");
         exit(1);
     }
     
@@ -32,17 +61,94 @@ fn main() {
 
             let parser_res = parser::parse(&contents);
 
-            let ast = match parser_res {
+            let mut ast = match parser_res {
                 Ok(a) => a,
                 Err(_) => exit(1)
             };
 
+            let emit = flags.iter().any(|f| f == "--emit-optimized-asm");
+
+            if emit || flags.iter().any(|f| f == "-O") {
+                ast = optimizer::optimize(ast);
+            }
+
+            if emit {
+                for node in &ast {
+                    println!("{}", node.data.to_source());
+                }
+            }
+
             if compiler::compile(&ast, &util::change_file_extension(&args[2], FILE_EXTENSION)).is_err() {
                 eprintln!("Couldn't write to file.");
                 exit(1);
             }
         }
 
+        "check" => {
+            let contents = match fs::read_to_string(&args[2]).ok() {
+                Some(c) => c,
+                None => {
+                    eprintln!("Couldn't read file '{}'", &args[2]);
+                    exit(1);
+                }
+            };
+
+            let ast = match parser::parse(&contents) {
+                Ok(a) => a,
+                Err(_) => exit(1)
+            };
+
+            if checker::check(&ast).is_err() {
+                exit(1);
+            }
+        }
+
+        "stats" => {
+            let contents = match fs::read_to_string(&args[2]).ok() {
+                Some(c) => c,
+                None => {
+                    eprintln!("Couldn't read file '{}'", &args[2]);
+                    exit(1);
+                }
+            };
+
+            let ast = match parser::parse(&contents) {
+                Ok(a) => a,
+                Err(_) => exit(1)
+            };
+
+            stats::stats(&ast);
+        }
+
+        "run" if flags.iter().any(|f| f == "--stream") => {
+            let file = match File::open(&args[2]) {
+                Ok(f) => f,
+                Err(_) => {
+                    eprintln!("Couldn't read file '{}'", &args[2]);
+                    exit(1);
+                }
+            };
+
+            // Safety: the file must not be modified or truncated while the program runs. The
+            // mapping reads it as it is on disk, so another process changing it would change
+            // memory the program holds references into, which is undefined behaviour; nothing
+            // here can prevent that, so running with `--stream` relies on it not happening
+            let contents = match unsafe { Mmap::map(&file) } {
+                Ok(m) => m,
+                Err(_) => {
+                    eprintln!("Couldn't read file '{}'", &args[2]);
+                    exit(1);
+                }
+            };
+
+            let bytecode = match parser::Bytecode::load(&contents) {
+                Ok(b) => b,
+                Err(_) => exit(1)
+            };
+
+            run(&bytecode, &flags);
+        }
+
         "run" => {
             let contents = match fs::read(&args[2]).ok() {
                 Some(c) => c,
@@ -59,11 +165,74 @@ fn main() {
                 Err(_) => exit(1)
             };
 
-            if interpreter::interpret(&ast).is_err() {
-                exit(1);
-            }
+            run(ast.as_slice(), &flags);
         }
         
-        s => eprintln!("Invalid option: '{s}'. Available options: 'assemble', 'run'.")
+        "disassemble" => {
+            let contents = match fs::read(&args[2]).ok() {
+                Some(c) => c,
+                None => {
+                    eprintln!("Couldn't read file '{}'", &args[2]);
+                    exit(1);
+                }
+            };
+
+            let ast = match parser::parse_reduced(&contents) {
+                Ok(a) => a,
+                Err(_) => exit(1)
+            };
+
+            let expand = flags.iter().any(|f| f == "--expand");
+
+            for node in &ast {
+                match node.0.expand() {
+                    Some(instructions) if expand => instructions.iter().for_each(|i| println!("{}", i.to_source())),
+                    _ => println!("{}", node.0.to_source()),
+                }
+            }
+        }
+
+        s => eprintln!("Invalid option: '{s}'. Available options: 'assemble', 'run', 'check', 'stats', 'disassemble'.")
+    }
+}
+
+fn run<P: interpreter::Program + ?Sized>(program: &P, flags: &[String]) {
+    let options = interpreter::Options {
+        undefined_as_nil: flags.iter().any(|f| f == "--undefined-as-nil"),
+        strict: flags.iter().any(|f| f == "--strict"),
+    };
+
+    let registers = if flags.iter().any(|f| f == "--registers") {
+        match register::translate(program) {
+            Ok(r) => Some(r),
+            Err(_) => exit(1)
+        }
+    }
+    else {
+        None
+    };
+
+    // only count what the program allocates, not what loading it did
+    #[cfg(feature = "alloc-stats")]
+    let (allocations, bytes) = allocator::allocations();
+
+    let result = match &registers {
+        Some(r) => register::interpret(r, &options),
+        None => interpreter::interpret(program, &options)
+    };
+
+    let stats = match result {
+        Ok(s) => s,
+        Err(_) => exit(1)
+    };
+
+    if flags.iter().any(|f| f == "--heap-stats") {
+        eprintln!("Heap: {} live objects, ~{} bytes, {} collections", stats.live_objects, stats.bytes, stats.collections);
+    }
+
+    #[cfg(feature = "alloc-stats")]
+    if flags.iter().any(|f| f == "--alloc-stats") {
+        let (a, b) = allocator::allocations();
+        eprintln!("Allocations: {} ({} bytes), peak {} bytes including loading", a - allocations, b - bytes, allocator::peak());
     }
 }
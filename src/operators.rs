@@ -0,0 +1,410 @@
+use std::cmp::Ordering;
+
+use crate::{ast::{AstNodeData, Value}, compare};
+
+/// Number of `Value` variants, which are used as indices into the table.
+const TYPES: usize = 9;
+
+// `Value` discriminants, in declaration order
+const NUM: u8 = 0;
+const STR: u8 = 1;
+const BOOL: u8 = 2;
+const NIL: u8 = 7;
+const ANY: u8 = u8::MAX; // registers an implementation for every type
+
+type Implementation = fn(&Value, &Value) -> Result<Value, String>;
+
+/// A binary instruction: how to apply it to each pair of operand types, and what to say
+/// when it is applied to a pair it doesn't support.
+struct Operator {
+  implementations: [[Option<Implementation>; TYPES]; TYPES],
+  error: fn(&Value, &Value) -> String,
+}
+
+/// Every binary instruction, indexed by opcode and by the types of its operands.
+///
+/// `a` is always the value on top of the stack and `b` the one below it. Looking up a pair
+/// of types an instruction wasn't registered for gives an error, so every combination either
+/// produces a value or fails; supporting a new type only takes registering its pairs in `new`.
+pub struct OperatorTable {
+  operators: Vec<Option<Operator>>,
+}
+
+impl OperatorTable {
+  pub fn new() -> Self {
+    let mut table = Self { operators: vec![] };
+
+    table.operator(AstNodeData::Add, |a, b| format!("Cannot add {} and {}", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Add, NUM, NUM, |a, b| Ok(Value::Num(num(a) + num(b))));
+    table.register(AstNodeData::Add, STR, STR, |a, b| Ok(Value::Str(format!("{}{}", str(a), str(b)).into())));
+
+    table.operator(AstNodeData::Sub, |a, b| format!("Cannot subtract {} and {}", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Sub, NUM, NUM, |a, b| Ok(Value::Num(num(a) - num(b))));
+
+    table.operator(AstNodeData::Mul, |a, b| format!("Cannot multiply {} and {}", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Mul, NUM, NUM, |a, b| Ok(Value::Num(num(a) * num(b))));
+
+    table.operator(AstNodeData::Div, |a, b| format!("Cannot divide {} and {}", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Div, NUM, NUM, |a, b| match num(b) {
+      0.0 => Err("Cannot divide by zero".into()),
+      b => Ok(Value::Num(num(a) / b)),
+    });
+
+    table.operator(AstNodeData::Mod, |a, b| format!("Cannot take the remainder of {} and {}", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Mod, NUM, NUM, |a, b| match num(b) {
+      0.0 => Err("Cannot take the remainder of a division by zero".into()),
+      b => Ok(Value::Num(num(a) % b)),
+    });
+
+    table.operator(AstNodeData::Pow, |a, b| format!("Cannot raise {} to {}", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Pow, NUM, NUM, |a, b| match (num(a), num(b)) {
+      (a, b) if a == 0.0 && b < 0.0 => Err("Cannot raise zero to a negative power (division by zero)".into()),
+      (a, b) => Ok(Value::Num(a.powf(b))),
+    });
+
+    // `f64::min` and `f64::max` ignore NaN; Machina propagates it instead
+    table.operator(AstNodeData::Min, |a, b| format!("Cannot take the minimum of {} and {}", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Min, NUM, NUM, |a, b| match (num(a), num(b)) {
+      (a, b) if a.is_nan() || b.is_nan() => Ok(Value::Num(f64::NAN)),
+      (a, b) => Ok(Value::Num(a.min(b))),
+    });
+
+    table.operator(AstNodeData::Max, |a, b| format!("Cannot take the maximum of {} and {}", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Max, NUM, NUM, |a, b| match (num(a), num(b)) {
+      (a, b) if a.is_nan() || b.is_nan() => Ok(Value::Num(f64::NAN)),
+      (a, b) => Ok(Value::Num(a.max(b))),
+    });
+
+    let order_error = |a: &Value, b: &Value| format!("Cannot order {} and {} (only numbers and strings can be ordered, and both must be of the same type)", a.as_str_debug(), b.as_str_debug());
+
+    let ordered: [(AstNodeData, Implementation); 4] = [
+      (AstNodeData::Cmpg, |a, b| Ok(Value::Bool(matches!(order(a, b), Some(Ordering::Greater))))),
+      (AstNodeData::Cmpge, |a, b| Ok(Value::Bool(matches!(order(a, b), Some(Ordering::Greater | Ordering::Equal))))),
+      (AstNodeData::Cmpl, |a, b| Ok(Value::Bool(matches!(order(a, b), Some(Ordering::Less))))),
+      (AstNodeData::Cmple, |a, b| Ok(Value::Bool(matches!(order(a, b), Some(Ordering::Less | Ordering::Equal))))),
+    ];
+
+    for (data, implementation) in ordered {
+      table.operator(data.clone(), order_error);
+      table.register(data.clone(), NUM, NUM, implementation);
+      table.register(data, STR, STR, implementation);
+    }
+
+    table.operator(AstNodeData::Cmpe, |a, b| format!("Cannot compare {} and {} as equal (they must be of the same type)", a.as_str_debug(), b.as_str_debug()));
+    table.operator(AstNodeData::Cmpne, |a, b| format!("Cannot compare {} and {} as not equal (they must be of the same type)", a.as_str_debug(), b.as_str_debug()));
+
+    // every type can be compared with itself, and nil with every type
+    for t in 0..TYPES as u8 {
+      table.register(AstNodeData::Cmpe, t, t, |a, b| Ok(Value::Bool(equal(a, b))));
+      table.register(AstNodeData::Cmpne, t, t, |a, b| Ok(Value::Bool(!equal(a, b))));
+    }
+
+    for (lhs, rhs) in [(NIL, ANY), (ANY, NIL)] {
+      table.register(AstNodeData::Cmpe, lhs, rhs, |a, b| Ok(Value::Bool(equal(a, b))));
+      table.register(AstNodeData::Cmpne, lhs, rhs, |a, b| Ok(Value::Bool(!equal(a, b))));
+    }
+
+    table.operator(AstNodeData::Land, |a, b| format!("Cannot apply logical and to {} and {} (they must be booleans)", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Land, BOOL, BOOL, |a, b| Ok(Value::Bool(bool(a) && bool(b))));
+
+    table.operator(AstNodeData::Lor, |a, b| format!("Cannot apply logical or to {} and {} (they must be booleans)", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Lor, BOOL, BOOL, |a, b| Ok(Value::Bool(bool(a) || bool(b))));
+
+    table.operator(AstNodeData::Lxor, |a, b| format!("Cannot apply logical xor to {} and {} (they must be booleans)", a.as_str_debug(), b.as_str_debug()));
+    table.register(AstNodeData::Lxor, BOOL, BOOL, |a, b| Ok(Value::Bool(bool(a) != bool(b))));
+
+    table
+  }
+
+  /// Applies the binary instruction with this opcode to `a` (the top of the stack) and `b`.
+  pub fn apply(&self, opcode: u8, a: &Value, b: &Value) -> Result<Value, String> {
+    let operator = self.operators[opcode as usize].as_ref().expect("not a binary instruction");
+
+    match operator.implementations[a.discriminant() as usize][b.discriminant() as usize] {
+      Some(implementation) => implementation(a, b),
+      None => Err((operator.error)(a, b)),
+    }
+  }
+
+  /// Whether the instruction with this opcode is a binary instruction in the table.
+  pub fn contains(&self, opcode: u8) -> bool {
+    matches!(self.operators.get(opcode as usize), Some(Some(_)))
+  }
+
+  fn operator(&mut self, data: AstNodeData, error: fn(&Value, &Value) -> String) {
+    let opcode = data.discriminant() as usize;
+
+    if self.operators.len() <= opcode {
+      self.operators.resize_with(opcode + 1, || None);
+    }
+
+    self.operators[opcode] = Some(Operator { implementations: [[None; TYPES]; TYPES], error });
+  }
+
+  fn register(&mut self, data: AstNodeData, lhs: u8, rhs: u8, implementation: Implementation) {
+    let operator = self.operators[data.discriminant() as usize].as_mut().expect("operator must be declared first");
+
+    let types = |t: u8| if t == ANY { 0..TYPES } else { t as usize..t as usize + 1 };
+
+    for a in types(lhs) {
+      for b in types(rhs) {
+        operator.implementations[a][b] = Some(implementation);
+      }
+    }
+  }
+}
+
+/// Applies the unary instruction `instruction` to `x`, the value on top of the stack.
+///
+/// The optimizer folds constants with it, so they're computed the same way as when running.
+pub fn unary(instruction: &AstNodeData, x: &Value) -> Result<Value, String> {
+  match (instruction, x) {
+    (AstNodeData::Neg, Value::Num(n)) => Ok(Value::Num(-n)),
+    (AstNodeData::Neg, x) => Err(format!("Cannot negate {}", x.as_str_debug())),
+
+    (AstNodeData::Abs, Value::Num(n)) => Ok(Value::Num(n.abs())),
+    (AstNodeData::Abs, x) => Err(format!("Cannot take the absolute value of {}", x.as_str_debug())),
+
+    (AstNodeData::Floor, Value::Num(n)) => Ok(Value::Num(n.floor())),
+    (AstNodeData::Floor, x) => Err(format!("Cannot round down {}", x.as_str_debug())),
+
+    (AstNodeData::Ceil, Value::Num(n)) => Ok(Value::Num(n.ceil())),
+    (AstNodeData::Ceil, x) => Err(format!("Cannot round up {}", x.as_str_debug())),
+
+    (AstNodeData::Round, Value::Num(n)) => Ok(Value::Num(n.round())),
+    (AstNodeData::Round, x) => Err(format!("Cannot round {}", x.as_str_debug())),
+
+    (AstNodeData::Sqrt, Value::Num(n)) if *n < 0.0 => Err(format!("Cannot take the square root of a negative number ({})", n)),
+    (AstNodeData::Sqrt, Value::Num(n)) => Ok(Value::Num(n.sqrt())),
+    (AstNodeData::Sqrt, x) => Err(format!("Cannot take the square root of {}", x.as_str_debug())),
+
+    (AstNodeData::Lnot, Value::Bool(b)) => Ok(Value::Bool(!b)),
+    (AstNodeData::Lnot, x) => Err(format!("Cannot apply logical not to {} (it must be a boolean)", x.as_str_debug())),
+
+    _ => unreachable!("not a unary instruction"),
+  }
+}
+
+/// Whether `unary` can apply this instruction.
+pub fn is_unary(instruction: &AstNodeData) -> bool {
+  matches!(instruction, AstNodeData::Neg | AstNodeData::Abs | AstNodeData::Floor | AstNodeData::Ceil | AstNodeData::Round | AstNodeData::Sqrt | AstNodeData::Lnot)
+}
+
+// the table only calls implementations with the types they were registered for
+
+fn num(v: &Value) -> f64 {
+  match v { Value::Num(n) => *n, _ => unreachable!() }
+}
+
+fn str(v: &Value) -> &str {
+  match v { Value::Str(s) => s, _ => unreachable!() }
+}
+
+fn bool(v: &Value) -> bool {
+  match v { Value::Bool(b) => *b, _ => unreachable!() }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+  compare::equals(a, b).expect("registered pairs are always comparable")
+}
+
+fn order(a: &Value, b: &Value) -> Option<Ordering> {
+  compare::order(a, b).expect("registered pairs can always be ordered")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+  use super::{OperatorTable, TYPES};
+  use crate::{ast::{AstNodeData, Value, Var}, heap::Record};
+
+  const COMPARISONS: [AstNodeData<'static>; 6] = [AstNodeData::Cmpe, AstNodeData::Cmpne, AstNodeData::Cmpg, AstNodeData::Cmpge, AstNodeData::Cmpl, AstNodeData::Cmple];
+
+  fn list(items: Vec<Value>) -> Value {
+    Value::List(Rc::new(RefCell::new(items)))
+  }
+
+  fn map(entries: &[(&str, Value)]) -> Value {
+    Value::Map(Rc::new(RefCell::new(entries.iter().map(|(k, v)| (k.to_string(), v.clone())).collect::<BTreeMap<_, _>>())))
+  }
+
+  fn record(name: &str, fields: &[(&str, Value)]) -> Value {
+    let fields = fields.iter().map(|(f, v)| (Rc::from(*f), v.clone())).collect();
+    Value::Record(Rc::new(RefCell::new(Record { name: name.into(), fields })))
+  }
+
+  fn reference(name: &str, scope: usize) -> Value {
+    Value::Ref(Var { name: name.into(), slot: 0 }, scope)
+  }
+
+  fn label(name: &str) -> Value {
+    Value::Label(name.into())
+  }
+
+  fn string(s: &str) -> Value {
+    Value::Str(s.into())
+  }
+
+  /// A value of every type, in the order of the `Value` variants.
+  fn samples() -> Vec<Value> {
+    vec![
+      Value::Num(1.0),
+      string("a"),
+      Value::Bool(true),
+      label("#a"),
+      list(vec![Value::Num(1.0)]),
+      map(&[("k", Value::Num(1.0))]),
+      reference("x", 0),
+      Value::Nil,
+      record("Point", &[("x", Value::Num(1.0))]),
+    ]
+  }
+
+  /// Applies a comparison to `a` (the top of the stack) and `b`, giving `None` if it's an error.
+  fn compare(table: &OperatorTable, op: &AstNodeData, a: &Value, b: &Value) -> Option<bool> {
+    match table.apply(op.discriminant(), a, b) {
+      Ok(Value::Bool(result)) => Some(result),
+      Ok(v) => panic!("'{}' gave {}", op.name(), v.as_str_debug()),
+      Err(_) => None,
+    }
+  }
+
+  #[test]
+  fn samples_cover_every_type() {
+    let samples = samples();
+
+    assert_eq!(samples.len(), TYPES);
+    assert!(samples.iter().enumerate().all(|(i, v)| v.discriminant() as usize == i));
+  }
+
+  #[test]
+  fn every_pair_of_types_either_compares_or_fails() {
+    let table = OperatorTable::new();
+    let (a_samples, b_samples) = (samples(), samples());
+
+    for a in &a_samples {
+      for b in &b_samples {
+        let same = a.discriminant() == b.discriminant();
+        let nil = matches!(a, Value::Nil) || matches!(b, Value::Nil);
+        let ordered = same && matches!(a, Value::Num(_) | Value::Str(_));
+
+        for op in &COMPARISONS {
+          let comparable = match op {
+            AstNodeData::Cmpe | AstNodeData::Cmpne => same || nil,
+            _ => ordered,
+          };
+
+          let result = compare(&table, op, a, b);
+          assert_eq!(result.is_some(), comparable, "'{}' on {} and {}", op.name(), a.as_str_debug(), b.as_str_debug());
+
+          // every sample is equal to another sample of its type, and only to it
+          match (op, result) {
+            (AstNodeData::Cmpe, Some(equal)) => assert_eq!(equal, same),
+            (AstNodeData::Cmpne, Some(different)) => assert_eq!(different, !same),
+            _ => {}
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn nil_is_only_equal_to_itself() {
+    let table = OperatorTable::new();
+
+    assert_eq!(compare(&table, &AstNodeData::Cmpe, &Value::Nil, &Value::Nil), Some(true));
+    assert_eq!(compare(&table, &AstNodeData::Cmpne, &Value::Nil, &Value::Nil), Some(false));
+
+    for v in samples().iter().filter(|v| !matches!(v, Value::Nil)) {
+      assert_eq!(compare(&table, &AstNodeData::Cmpe, v, &Value::Nil), Some(false));
+      assert_eq!(compare(&table, &AstNodeData::Cmpe, &Value::Nil, v), Some(false));
+      assert_eq!(compare(&table, &AstNodeData::Cmpne, v, &Value::Nil), Some(true));
+    }
+  }
+
+  #[test]
+  fn scalars_are_compared_by_value() {
+    let table = OperatorTable::new();
+    let equal = |a: Value, b: Value| compare(&table, &AstNodeData::Cmpe, &a, &b);
+
+    assert_eq!(equal(Value::Num(2.0), Value::Num(2.0)), Some(true));
+    assert_eq!(equal(Value::Num(f64::NAN), Value::Num(f64::NAN)), Some(false));
+    assert_eq!(equal(string("a"), string("a")), Some(true));
+    assert_eq!(equal(string("a"), string("b")), Some(false));
+    assert_eq!(equal(Value::Bool(false), Value::Bool(true)), Some(false));
+    assert_eq!(equal(label("#a"), label("#a")), Some(true));
+    assert_eq!(equal(label("#a"), label("#b")), Some(false));
+  }
+
+  #[test]
+  fn references_are_equal_when_they_point_to_the_same_variable() {
+    let table = OperatorTable::new();
+    let equal = |a: Value, b: Value| compare(&table, &AstNodeData::Cmpe, &a, &b);
+
+    assert_eq!(equal(reference("x", 1), reference("x", 1)), Some(true));
+    assert_eq!(equal(reference("x", 1), reference("x", 2)), Some(false));
+    assert_eq!(equal(reference("x", 1), reference("y", 1)), Some(false));
+  }
+
+  #[test]
+  fn objects_are_compared_by_contents() {
+    let table = OperatorTable::new();
+    let equal = |a: Value, b: Value| compare(&table, &AstNodeData::Cmpe, &a, &b);
+    let nested = |n: f64| list(vec![Value::Num(1.0), list(vec![Value::Num(2.0), Value::Num(n)])]);
+
+    assert_eq!(equal(nested(3.0), nested(3.0)), Some(true));
+    assert_eq!(equal(nested(3.0), nested(4.0)), Some(false));
+    assert_eq!(equal(list(vec![Value::Num(1.0)]), list(vec![Value::Num(1.0), Value::Num(1.0)])), Some(false));
+
+    // items of different types make the objects different, not the comparison fail
+    assert_eq!(equal(list(vec![Value::Num(1.0)]), list(vec![string("1")])), Some(false));
+    assert_eq!(equal(list(vec![Value::Nil]), list(vec![Value::Nil])), Some(true));
+
+    assert_eq!(equal(map(&[("a", Value::Num(1.0))]), map(&[("a", Value::Num(1.0))])), Some(true));
+    assert_eq!(equal(map(&[("a", Value::Num(1.0))]), map(&[("b", Value::Num(1.0))])), Some(false));
+    assert_eq!(equal(map(&[("a", nested(3.0))]), map(&[("a", nested(3.0))])), Some(true));
+
+    assert_eq!(equal(record("P", &[("x", Value::Num(1.0))]), record("P", &[("x", Value::Num(1.0))])), Some(true));
+    assert_eq!(equal(record("P", &[("x", Value::Num(1.0))]), record("Q", &[("x", Value::Num(1.0))])), Some(false));
+  }
+
+  #[test]
+  fn cyclic_lists_are_equal_when_they_have_the_same_shape() {
+    let table = OperatorTable::new();
+
+    // each list holds a number and then itself
+    let cyclic = |n: f64| {
+      let list = Rc::new(RefCell::new(vec![Value::Num(n)]));
+      list.borrow_mut().push(Value::List(list.clone()));
+      list
+    };
+
+    let (a, b, c) = (cyclic(1.0), cyclic(1.0), cyclic(2.0));
+    let (a_value, b_value, c_value) = (Value::List(a.clone()), Value::List(b.clone()), Value::List(c.clone()));
+
+    assert_eq!(compare(&table, &AstNodeData::Cmpe, &a_value, &a_value), Some(true));
+    assert_eq!(compare(&table, &AstNodeData::Cmpe, &a_value, &b_value), Some(true));
+    assert_eq!(compare(&table, &AstNodeData::Cmpe, &a_value, &c_value), Some(false));
+    assert_eq!(compare(&table, &AstNodeData::Cmpne, &a_value, &c_value), Some(true));
+
+    // break the cycles, so the lists are freed
+    for list in [a, b, c] {
+      list.borrow_mut().clear();
+    }
+  }
+
+  #[test]
+  fn numbers_and_strings_are_ordered() {
+    let table = OperatorTable::new();
+    let results = |a: Value, b: Value| COMPARISONS[2..].iter().map(|op| compare(&table, op, &a, &b)).collect::<Vec<_>>();
+
+    // cmpg, cmpge, cmpl and cmple, with `a` on top
+    assert_eq!(results(Value::Num(2.0), Value::Num(1.0)), [Some(true), Some(true), Some(false), Some(false)]);
+    assert_eq!(results(Value::Num(1.0), Value::Num(1.0)), [Some(false), Some(true), Some(false), Some(true)]);
+    assert_eq!(results(Value::Num(f64::NAN), Value::Num(1.0)), [Some(false); 4]);
+    assert_eq!(results(string("b"), string("a")), [Some(true), Some(true), Some(false), Some(false)]);
+    assert_eq!(results(string("a"), string("ab")), [Some(false), Some(false), Some(true), Some(true)]);
+    assert_eq!(results(string("Z"), string("a")), [Some(false), Some(false), Some(true), Some(true)]);
+  }
+}
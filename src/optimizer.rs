@@ -0,0 +1,336 @@
+use std::collections::HashSet;
+
+use crate::{ast::{AstNode, AstNodeData, Comparison, Value}, operators::{self, OperatorTable}};
+
+/// What rewrites need to know about the whole program.
+struct Context {
+  operators: OperatorTable,
+  labels: HashSet<String>, // every label declared in the program
+}
+
+/// Rewrites the program into one with the same output, but fewer or cheaper instructions.
+///
+/// Every rewrite replaces a run of adjacent instructions. Jumps only land on labels, which are
+/// instructions of their own, so a run without labels in the middle always executes as a whole.
+/// A program that stops with an error still does, though the message may name another instruction.
+pub fn optimize(mut ast: Vec<AstNode>) -> Vec<AstNode> {
+  let mut operators = OperatorTable::new();
+
+  // a rewrite can make a new run match, and removing code can bring runs together,
+  // so go until nothing changes
+  loop {
+    let context = Context { operators, labels: labels(&ast) };
+    let (rewritten, changed) = pass(ast, &context);
+    let (pruned, pruned_any) = prune(rewritten);
+
+    ast = pruned;
+
+    if !changed && !pruned_any {
+      return ast;
+    }
+
+    operators = context.operators;
+  }
+}
+
+fn pass(ast: Vec<AstNode>, context: &Context) -> (Vec<AstNode>, bool) {
+  let mut output: Vec<AstNode> = Vec::with_capacity(ast.len());
+  let mut changed = false;
+  let mut i = 0;
+
+  while i < ast.len() {
+    match rewrite(&ast[i..], context) {
+      Some((len, replacement)) => {
+        let first = &ast[i];
+
+        for data in replacement {
+          output.push(AstNode::new(data, first.code.clone(), first.line));
+        }
+
+        i += len;
+        changed = true;
+      }
+      None => {
+        output.push(AstNode::new(ast[i].data.clone(), ast[i].code.clone(), ast[i].line));
+        i += 1;
+      }
+    }
+  }
+
+  (output, changed)
+}
+
+/// Finds a rewrite for the instructions at the start of `ast`, returning how many of them it
+/// replaces and what with.
+fn rewrite(ast: &[AstNode], context: &Context) -> Option<(usize, Vec<AstNodeData<'static>>)> {
+  // as long as the longest rewrite; shorter ones end with `..` to match wherever they are
+  let data: Vec<&AstNodeData> = ast.iter().take(5).map(|n| &n.data).collect();
+
+  match data.as_slice() {
+    // computed the same way the interpreter would; operations that fail are left for it to report
+    [AstNodeData::Pushc(b), AstNodeData::Pushc(a), op, ..] if context.operators.contains(op.discriminant()) => {
+      let value = context.operators.apply(op.discriminant(), a, b).ok()?;
+      Some((3, vec![AstNodeData::Pushc(value)]))
+    }
+    [AstNodeData::Pushc(x), op, ..] if operators::is_unary(op) => {
+      let value = operators::unary(op, x).ok()?;
+      Some((2, vec![AstNodeData::Pushc(value)]))
+    }
+
+    // a jump to a label that doesn't exist fails even when it's not taken
+    [AstNodeData::Pushc(Value::Bool(b)), AstNodeData::Pushc(Value::Label(l)), jump @ (AstNodeData::Jt | AstNodeData::Jf), ..] if context.labels.contains(&**l) => {
+      match (b, jump) {
+        (true, AstNodeData::Jt) | (false, AstNodeData::Jf) => Some((3, vec![AstNodeData::Pushc(Value::Label(l.clone())), AstNodeData::Jmp])),
+        _ => Some((3, vec![])),
+      }
+    }
+
+    // execution gets to the label anyway
+    [AstNodeData::Pushc(Value::Label(l)), AstNodeData::Jmp, AstNodeData::Label(m), ..] if **l == **m => Some((3, vec![AstNodeData::Label(m.clone())])),
+
+    // the variable holds what was on the stack, so it can be kept there instead of read back
+    [AstNodeData::Popv(a), AstNodeData::Pushv(b), ..] if a == b => Some((2, vec![AstNodeData::Dup, AstNodeData::Popv(a.clone())])),
+
+    [AstNodeData::Pushc(Value::Num(n)), AstNodeData::Add, ..] if *n == 1.0 => Some((2, vec![AstNodeData::Inc])),
+    [AstNodeData::Pushc(Value::Num(n)), AstNodeData::Add, ..] if *n == -1.0 => Some((2, vec![AstNodeData::Dec])),
+
+    [AstNodeData::Cmpe, AstNodeData::Lnot, ..] => Some((2, vec![AstNodeData::Cmpne])),
+    [AstNodeData::Cmpne, AstNodeData::Lnot, ..] => Some((2, vec![AstNodeData::Cmpe])),
+
+    // both fail the same way on something that isn't a boolean
+    [AstNodeData::Lnot, AstNodeData::Pushc(label @ Value::Label(_)), AstNodeData::Jt, ..] => Some((3, vec![AstNodeData::Pushc(label.clone()), AstNodeData::Jf])),
+    [AstNodeData::Lnot, AstNodeData::Pushc(label @ Value::Label(_)), AstNodeData::Jf, ..] => Some((3, vec![AstNodeData::Pushc(label.clone()), AstNodeData::Jt])),
+
+    // pushing a constant can't fail, so it can go along with its pop
+    [AstNodeData::Pushc(_), AstNodeData::Pop, ..] => Some((2, vec![])),
+
+    // superinstructions, which do the same in one dispatch
+    [AstNodeData::Pushv(a), AstNodeData::Inc, AstNodeData::Popv(b), ..] if a == b => Some((3, vec![AstNodeData::Incv(a.clone())])),
+    [AstNodeData::Pushv(a), AstNodeData::Dec, AstNodeData::Popv(b), ..] if a == b => Some((3, vec![AstNodeData::Decv(a.clone())])),
+
+    // lists and maps are left out, since pushing them makes a new one every time
+    [AstNodeData::Pushv(var), AstNodeData::Pushc(value), cmp, AstNodeData::Pushc(Value::Label(l)), AstNodeData::Jt]
+      if Comparison::of(cmp).is_some() && !matches!(value, Value::List(_) | Value::Map(_)) => {
+      Comparison::of(cmp).map(|cmp| (5, vec![AstNodeData::Jcmp(cmp, var.clone(), value.clone(), l.to_string().into())]))
+    }
+
+    _ => None,
+  }
+}
+
+/// Removes the instructions that can't be reached, and the labels nothing refers to.
+///
+/// Execution can only start at the first instruction or at a label something refers to, and
+/// only `jmp` keeps it from going on to the next instruction, so everything after a `jmp` is
+/// unreachable until the next label that's referred to.
+fn prune(ast: Vec<AstNode>) -> (Vec<AstNode>, bool) {
+  let referenced = referenced_labels(&ast);
+  let len = ast.len();
+
+  let mut output: Vec<AstNode> = Vec::with_capacity(len);
+  let mut reachable = true;
+
+  for node in ast {
+    match &node.data {
+      // structs are collected before running, wherever they are
+      AstNodeData::Struct(..) => {}
+
+      AstNodeData::Label(name) => match &referenced {
+        Some(labels) if !labels.contains(&**name) => continue,
+        _ => reachable = true,
+      },
+
+      _ if !reachable => continue,
+      AstNodeData::Jmp => reachable = false,
+      _ => {}
+    }
+
+    output.push(node);
+  }
+
+  let pruned = output.len() != len;
+  (output, pruned)
+}
+
+/// Every label the program refers to, or `None` if it might jump to any of them, because it
+/// makes labels out of strings with `tolabel`.
+fn referenced_labels(ast: &[AstNode]) -> Option<HashSet<String>> {
+  let mut labels = HashSet::new();
+
+  for node in ast {
+    match &node.data {
+      AstNodeData::Tolabel => return None,
+
+      AstNodeData::Pushc(value)
+      | AstNodeData::Setc(_, value)
+      | AstNodeData::Const(_, value) => collect_labels(value, &mut labels),
+
+      AstNodeData::Jcmp(_, _, value, label) => {
+        collect_labels(value, &mut labels);
+        labels.insert(label.to_string());
+      }
+
+      _ => {}
+    }
+  }
+
+  Some(labels)
+}
+
+/// Adds the labels in a constant, including those in its lists and maps.
+pub fn collect_labels(value: &Value, labels: &mut HashSet<String>) {
+  match value {
+    Value::Label(l) => { labels.insert(l.to_string()); }
+    Value::List(list) => list.borrow().iter().for_each(|v| collect_labels(v, labels)),
+    Value::Map(map) => map.borrow().values().for_each(|v| collect_labels(v, labels)),
+    _ => {}
+  }
+}
+
+fn labels(ast: &[AstNode]) -> HashSet<String> {
+  ast.iter()
+    .filter_map(|n| match &n.data {
+      AstNodeData::Label(name) => Some(name.to_string()),
+      _ => None,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::optimize;
+  use crate::parser::parse;
+
+  fn optimized(source: &str) -> Vec<String> {
+    let ast = parse(source).expect("test programs are valid");
+    optimize(ast).iter().map(|n| n.data.to_source()).collect()
+  }
+
+  // every rewrite of three instructions, with more after it, which the window must not hide
+
+  #[test]
+  fn folds_constants_followed_by_more() {
+    assert_eq!(optimized("pushc 10\npushc 20\nadd\nprintln\n"), ["pushc 30", "println"]);
+  }
+
+  #[test]
+  fn folds_constant_jumps_followed_by_more() {
+    assert_eq!(optimized("pushc false\npushc #l\njt\npushc 1\nprintln\n#l\npushc 2\nprintln\n"), ["pushc 1", "println", "pushc 2", "println"]);
+  }
+
+  #[test]
+  fn removes_jumps_to_the_next_label_followed_by_more() {
+    assert_eq!(optimized("pushc #l\njmp\n#l\npushc 1\nprintln\n"), ["pushc 1", "println"]);
+  }
+
+  #[test]
+  fn swaps_jumps_after_lnot_followed_by_more() {
+    assert_eq!(
+      optimized("setc b true\npushv b\nlnot\npushc #l\njt\npushc 1\nprintln\n#l\npushc 2\nprintln\n"),
+      ["setc b true", "pushv b", "pushc #l", "jf", "pushc 1", "println", "#l", "pushc 2", "println"],
+    );
+
+    assert_eq!(
+      optimized("setc b true\npushv b\nlnot\npushc #l\njf\npushc 1\nprintln\n#l\npushc 2\nprintln\n"),
+      ["setc b true", "pushv b", "pushc #l", "jt", "pushc 1", "println", "#l", "pushc 2", "println"],
+    );
+  }
+  #[test]
+  fn folds_constants() {
+    assert_eq!(optimized("pushc 3\npushc 2\nsub\n"), ["pushc -1"]);
+    assert_eq!(optimized("pushc \"b\"\npushc \"a\"\nadd\n"), ["pushc \"ab\""]);
+    assert_eq!(optimized("pushc \"a\"\npushc \"b\"\ncmpg\n"), ["pushc true"]);
+  }
+
+  #[test]
+  fn folds_unary_operations_on_constants() {
+    assert_eq!(optimized("pushc 2\nneg\n"), ["pushc -2"]);
+    assert_eq!(optimized("pushc -2\nabs\n"), ["pushc 2"]);
+    assert_eq!(optimized("pushc 2.5\nfloor\n"), ["pushc 2"]);
+    assert_eq!(optimized("pushc 2.5\nceil\n"), ["pushc 3"]);
+    assert_eq!(optimized("pushc 2.5\nround\n"), ["pushc 3"]);
+    assert_eq!(optimized("pushc 9\nsqrt\n"), ["pushc 3"]);
+    assert_eq!(optimized("pushc true\nlnot\n"), ["pushc false"]);
+    assert_eq!(optimized("pushc 2\npushc 3\nadd\nneg\nprintln\n"), ["pushc -5", "println"]);
+  }
+
+  #[test]
+  fn folds_folded_constants() {
+    assert_eq!(optimized("pushc 1\npushc 2\nadd\npushc 3\nmul\nprintln\n"), ["pushc 9", "println"]);
+  }
+
+  #[test]
+  fn leaves_failing_operations_for_the_interpreter() {
+    assert_eq!(optimized("pushc \"a\"\npushc 2\nmul\nprintln\n"), ["pushc \"a\"", "pushc 2", "mul", "println"]);
+    assert_eq!(optimized("pushc -1\nsqrt\nprintln\n"), ["pushc -1", "sqrt", "println"]);
+    assert_eq!(optimized("pushc \"a\"\nneg\nprintln\n"), ["pushc \"a\"", "neg", "println"]);
+    assert_eq!(optimized("pushc 1\nlnot\nprintln\n"), ["pushc 1", "lnot", "println"]);
+  }
+
+  #[test]
+  fn folds_constant_jumps() {
+    let program = |b, jump| format!("pushc {b}\npushc #l\n{jump}\npushc 1\nprintln\n#l\npushc 2\nprintln\n");
+
+    // taken: what's between the jump and the label can't be reached anymore
+    assert_eq!(optimized(&program("true", "jt")), ["pushc 2", "println"]);
+    assert_eq!(optimized(&program("false", "jf")), ["pushc 2", "println"]);
+
+    assert_eq!(optimized(&program("false", "jt")), ["pushc 1", "println", "pushc 2", "println"]);
+    assert_eq!(optimized(&program("true", "jf")), ["pushc 1", "println", "pushc 2", "println"]);
+  }
+
+  #[test]
+  fn leaves_jumps_to_missing_labels() {
+    assert_eq!(optimized("pushc false\npushc #nowhere\njt\n"), ["pushc false", "pushc #nowhere", "jt"]);
+  }
+
+  #[test]
+  fn removes_unreachable_code() {
+    assert_eq!(
+      optimized("pushc #a\njmp\npushc \"dead\"\nprintln\n#b\npushc \"b\"\nprintln\n#a\npushc #b\njmp\n"),
+      ["pushc #a", "jmp", "#b", "pushc \"b\"", "println", "#a", "pushc #b", "jmp"],
+    );
+  }
+
+  #[test]
+  fn keeps_unreachable_structs() {
+    assert_eq!(optimized("pushc #a\njmp\n.struct P x:num\npushc 1\nprintln\n#a\n"), ["pushc #a", "jmp", ".struct P x:num", "#a"]);
+  }
+
+  #[test]
+  fn removes_unused_labels() {
+    assert_eq!(optimized("#unused\npushc 1\nprintln\n"), ["pushc 1", "println"]);
+  }
+
+  #[test]
+  fn keeps_labels_in_constants() {
+    assert_eq!(optimized("setc l [#a]\n#a\n"), ["setc l [#a]", "#a"]);
+    assert_eq!(optimized("setc m {\"k\": #a}\n#a\n"), ["setc m {\"k\": #a}", "#a"]);
+  }
+
+  #[test]
+  fn keeps_every_label_with_tolabel() {
+    assert_eq!(optimized("pushc \"#a\"\ntolabel\nprintln\n#a\n#b\n"), ["pushc \"#a\"", "tolabel", "println", "#a", "#b"]);
+  }
+  #[test]
+  fn keeps_popped_values_on_the_stack() {
+    assert_eq!(optimized("pushc 1\npopv x\npushv x\nprintln\n"), ["pushc 1", "dup", "popv x", "println"]);
+  }
+
+  #[test]
+  fn adds_one_with_inc_and_dec() {
+    assert_eq!(optimized("pushv x\npushc 1\nadd\nprintln\n"), ["pushv x", "inc", "println"]);
+    assert_eq!(optimized("pushv x\npushc -1\nadd\nprintln\n"), ["pushv x", "dec", "println"]);
+  }
+
+  #[test]
+  fn negates_comparisons() {
+    assert_eq!(optimized("pushv x\npushv y\ncmpe\nlnot\nprintln\n"), ["pushv x", "pushv y", "cmpne", "println"]);
+    assert_eq!(optimized("pushv x\npushv y\ncmpne\nlnot\nprintln\n"), ["pushv x", "pushv y", "cmpe", "println"]);
+  }
+
+  #[test]
+  fn removes_pushed_constants_that_are_popped() {
+    assert_eq!(optimized("pushc \"unused\"\npop\npushc 1\nprintln\n"), ["pushc 1", "println"]);
+  }
+}
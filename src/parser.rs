@@ -1,4 +1,6 @@
-use crate::{ast::{AstNode, AstNodeData, ReducedAstNode, Value}, util::{is_identifier, is_label, print_error, print_error_reduced, custom_split}};
+use std::{borrow::Cow, cell::RefCell, collections::{BTreeMap, HashMap}, rc::Rc};
+
+use crate::{ast::{AstNode, AstNodeData, Comparison, ReducedAstNode, Type, Value, Var}, compiler::{PUSHC_FALSE, PUSHC_INT, PUSHC_NIL, PUSHC_SMALL, PUSHC_TRUE, SETC_FALSE, SETC_INT, SETC_TRUE}, util::{is_identifier, is_label, print_error, print_error_reduced, custom_split, split_literal_items, split_map_entry}};
 
 macro_rules! push_node {
     ($node: expr, $nodes: expr, $line: expr, $i: expr) => {
@@ -7,23 +9,83 @@ macro_rules! push_node {
 }
 
 macro_rules! parse_string {
+    ($bytes: expr, $count: expr, $strings: expr, $inst: literal) => {
+        {
+            let index = parse_varint!($bytes, $count, $inst);
+
+            match $strings.get(index as usize) {
+                Some(s) => *s,
+                None => {
+                    print_error_reduced(&format!("While parsing '{}' instruction: String {} isn't in the string section", $inst, index), *$count);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+macro_rules! parse_varint {
     ($bytes: expr, $count: expr, $inst: literal) => {
-        match parse_string($bytes, $count) {
+        match parse_varint($bytes, $count) {
             Some(n) => n,
             None => {
-                print_error_reduced(&format!("While parsing '{}' instruction: Bytecode size isn't long enough to properly parse a string", $inst), *$count);
+                print_error_reduced(&format!("While parsing '{}' instruction: Bytecode size isn't long enough to properly parse an integer", $inst), *$count);
                 return Err(());
             }
         }
     }
 }
 
-macro_rules! parse_value {
+macro_rules! parse_zigzag {
     ($bytes: expr, $count: expr, $inst: literal) => {
-        match parse_value_reduced($bytes, $count) {
+        match parse_zigzag($bytes, $count) {
             Some(n) => n,
             None => {
-                print_error_reduced(&format!("While parsing '{}' instruction: Bytecode size isn't long enough to properly parse a value", $inst), *$count);
+                print_error_reduced(&format!("While parsing '{}' instruction: Bytecode size isn't long enough to properly parse an integer", $inst), *$count);
+                return Err(());
+            }
+        }
+    }
+}
+
+macro_rules! parse_var {
+    ($bytes: expr, $count: expr, $symbols: expr, $inst: literal) => {
+        {
+            let slot = parse_varint!($bytes, $count, $inst);
+
+            match $symbols.get(slot as usize) {
+                Some(name) => Var { name: Rc::clone(name), slot },
+                None => {
+                    print_error_reduced(&format!("While parsing '{}' instruction: Variable slot {} isn't in the symbol section", $inst, slot), *$count);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+macro_rules! parse_constant {
+    ($bytes: expr, $count: expr, $constants: expr, $inst: literal) => {
+        {
+            let index = parse_varint!($bytes, $count, $inst);
+
+            match $constants.get(index as usize) {
+                Some(value) => value.clone(),
+                None => {
+                    print_error_reduced(&format!("While parsing '{}' instruction: Constant {} isn't in the constant section", $inst, index), *$count);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+macro_rules! parse_type {
+    ($bytes: expr, $count: expr, $strings: expr, $inst: literal) => {
+        match parse_type_reduced($bytes, $count, $strings) {
+            Some(t) => t,
+            None => {
+                print_error_reduced(&format!("While parsing '{}' instruction: Bytecode size isn't long enough to properly parse a type", $inst), *$count);
                 return Err(());
             }
         }
@@ -33,6 +95,7 @@ macro_rules! parse_value {
 pub fn parse(input: &str) -> Result<Vec<AstNode>, ()> {
     let mut had_error = false;
     let mut nodes: Vec<AstNode> = vec![];
+    let mut slots: HashMap<String, u32> = HashMap::new();
     
     for (i, line) in input.lines().enumerate() {
         if line.is_empty() {
@@ -55,7 +118,70 @@ pub fn parse(input: &str) -> Result<Vec<AstNode>, ()> {
                         break;
                     }
 
-                    push_node!(AstNodeData::Label(s.into()), nodes, line, i);
+                    push_node!(AstNodeData::Label(s.to_string().into()), nodes, line, i);
+                }
+
+                ".struct" => {
+                    if args.is_empty() {
+                        print_error("'.struct' directive requires a name", line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if !is_identifier(args[0]) {
+                        print_error(&format!("Identifier '{}' is not valid", args[0]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    // types are looked up by name, so a struct named like a builtin type could never be named
+                    if !matches!(Type::from_name(args[0]), Some(Type::Struct(_))) {
+                        print_error(&format!("Struct name '{}' is the name of a builtin type", args[0]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    let mut fields: Vec<(Cow<str>, Type)> = vec![];
+
+                    for field in &args[1..] {
+                        let (name, ty) = match field.split_once(':') {
+                            Some((name, ty)) if is_identifier(name) => (name, ty),
+                            _ => {
+                                print_error(&format!("Field '{}' is not valid (fields are written as 'name:type')", field), line, i);
+
+                                had_error = true;
+                                break;
+                            }
+                        };
+
+                        let ty = match Type::from_name(ty) {
+                            Some(t) => t,
+                            None => {
+                                print_error(&format!("Type '{}' of field '{}' is not valid", ty, name), line, i);
+
+                                had_error = true;
+                                break;
+                            }
+                        };
+
+                        if fields.iter().any(|(n, _)| n == name) {
+                            print_error(&format!("Field '{}' is declared more than once", name), line, i);
+
+                            had_error = true;
+                            break;
+                        }
+
+                        fields.push((name.to_string().into(), ty));
+                    }
+
+                    if had_error {
+                        break;
+                    }
+
+                    push_node!(AstNodeData::Struct(args[0].to_string().into(), fields), nodes, line, i);
                 }
 
                 "pushc" => {
@@ -94,7 +220,66 @@ pub fn parse(input: &str) -> Result<Vec<AstNode>, ()> {
                         break;
                     }
 
-                    push_node!(AstNodeData::Pushv(args[0].into()), nodes, line, i)
+                    push_node!(AstNodeData::Pushv(var(&mut slots, args[0])), nodes, line, i)
+                }
+
+                "incv" | "decv" => {
+                    if args.len() != 1 {
+                        print_error(&format!("'{inst}' instruction requires 1 argument, got {}", args.len()), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if !is_identifier(args[0]) {
+                        print_error(&format!("Identifier '{}' is not valid", args[0]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if inst == "incv" {
+                        push_node!(AstNodeData::Incv(var(&mut slots, args[0])), nodes, line, i);
+                    }
+                    else {
+                        push_node!(AstNodeData::Decv(var(&mut slots, args[0])), nodes, line, i);
+                    }
+                }
+
+                "jcmpg" | "jcmpge" | "jcmpl" | "jcmple" | "jcmpe" | "jcmpne" => {
+                    if args.len() != 3 {
+                        print_error(&format!("'{inst}' instruction requires 3 arguments, got {}", args.len()), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if !is_identifier(args[0]) {
+                        print_error(&format!("Identifier '{}' is not valid", args[0]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    let value = match parse_value(args[1], line, i) {
+                        Some(v) => v,
+                        None => {
+                            print_error(&format!("Couldn't parse value '{}'", args[1]), line, i);
+
+                            had_error = true;
+                            break
+                        }
+                    };
+
+                    if !is_label(args[2]) {
+                        print_error(&format!("Label identifier '{}' is not valid", args[2]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    let cmp = Comparison::from_mnemonic(inst).expect("matched a 'jcmp' mnemonic");
+                    push_node!(AstNodeData::Jcmp(cmp, var(&mut slots, args[0]), value, args[2].to_string().into()), nodes, line, i);
                 }
 
                 "setc" => {
@@ -122,7 +307,63 @@ pub fn parse(input: &str) -> Result<Vec<AstNode>, ()> {
                         }
                     };
 
-                    push_node!(AstNodeData::Setc(args[0].into(), value), nodes, line, i)
+                    push_node!(AstNodeData::Setc(var(&mut slots, args[0]), value), nodes, line, i)
+                }
+
+                "const" => {
+                    if args.len() != 2 {
+                        print_error(&format!("'const' instruction requires 2 arguments, got {}", args.len()), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if !is_identifier(args[0]) {
+                        print_error(&format!("Identifier '{}' is not valid", args[0]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    let value = match parse_value(args[1], line, i) {
+                        Some(v) => v,
+                        None => {
+                            print_error(&format!("Couldn't parse value '{}'", args[1]), line, i);
+
+                            had_error = true;
+                            break
+                        }
+                    };
+
+                    push_node!(AstNodeData::Const(var(&mut slots, args[0]), value), nodes, line, i)
+                }
+
+                "decl" => {
+                    if args.len() != 2 {
+                        print_error(&format!("'decl' instruction requires 2 arguments, got {}", args.len()), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if !is_identifier(args[0]) {
+                        print_error(&format!("Identifier '{}' is not valid", args[0]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    let ty = match Type::from_name(args[1]) {
+                        Some(t) => t,
+                        None => {
+                            print_error(&format!("Type '{}' is not valid", args[1]), line, i);
+
+                            had_error = true;
+                            break
+                        }
+                    };
+
+                    push_node!(AstNodeData::Decl(var(&mut slots, args[0]), ty), nodes, line, i)
                 }
 
                 "popv" => {
@@ -140,7 +381,7 @@ pub fn parse(input: &str) -> Result<Vec<AstNode>, ()> {
                         break;
                     }
 
-                    push_node!(AstNodeData::Popv(args[0].into()), nodes, line, i);
+                    push_node!(AstNodeData::Popv(var(&mut slots, args[0])), nodes, line, i);
                 }
 
                 "pop" => push_node!(AstNodeData::Pop, nodes, line, i),
@@ -169,12 +410,165 @@ pub fn parse(input: &str) -> Result<Vec<AstNode>, ()> {
                 "cmpe" => push_node!(AstNodeData::Cmpe, nodes, line, i),
                 "cmpne" => push_node!(AstNodeData::Cmpne, nodes, line, i),
 
-                "jmp" => push_node!(AstNodeData::Jmp, nodes, line, i),
-                "jt" => push_node!(AstNodeData::Jt, nodes, line, i),
-                "jf" => push_node!(AstNodeData::Jf, nodes, line, i),
+                "jmp" | "jt" | "jf" => {
+                    // `jt #label` is shorthand for `pushc #label` followed by `jt`
+                    if args.len() > 1 {
+                        print_error(&format!("'{inst}' instruction requires at most 1 argument, got {}", args.len()), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if let Some(label) = args.first() {
+                        if !is_label(label) {
+                            print_error(&format!("Label identifier '{}' is not valid", label), line, i);
+
+                            had_error = true;
+                            break;
+                        }
+
+                        push_node!(AstNodeData::Pushc(Value::Label((*label).into())), nodes, line, i);
+                    }
+
+                    match inst {
+                        "jmp" => push_node!(AstNodeData::Jmp, nodes, line, i),
+                        "jt" => push_node!(AstNodeData::Jt, nodes, line, i),
+                        _ => push_node!(AstNodeData::Jf, nodes, line, i),
+                    }
+                }
 
                 "save" => push_node!(AstNodeData::Save, nodes, line, i),
                 "ret" => push_node!(AstNodeData::Ret, nodes, line, i),
+
+                "mod" => push_node!(AstNodeData::Mod, nodes, line, i),
+                "pow" => push_node!(AstNodeData::Pow, nodes, line, i),
+                "neg" => push_node!(AstNodeData::Neg, nodes, line, i),
+                "abs" => push_node!(AstNodeData::Abs, nodes, line, i),
+                "min" => push_node!(AstNodeData::Min, nodes, line, i),
+                "max" => push_node!(AstNodeData::Max, nodes, line, i),
+
+                "floor" => push_node!(AstNodeData::Floor, nodes, line, i),
+                "ceil" => push_node!(AstNodeData::Ceil, nodes, line, i),
+                "round" => push_node!(AstNodeData::Round, nodes, line, i),
+                "sqrt" => push_node!(AstNodeData::Sqrt, nodes, line, i),
+
+                "land" => push_node!(AstNodeData::Land, nodes, line, i),
+                "lor" => push_node!(AstNodeData::Lor, nodes, line, i),
+                "lnot" => push_node!(AstNodeData::Lnot, nodes, line, i),
+                "lxor" => push_node!(AstNodeData::Lxor, nodes, line, i),
+
+                "dup" => push_node!(AstNodeData::Dup, nodes, line, i),
+                "swap" => push_node!(AstNodeData::Swap, nodes, line, i),
+                "over" => push_node!(AstNodeData::Over, nodes, line, i),
+                "rot" => push_node!(AstNodeData::Rot, nodes, line, i),
+
+                "drop" | "pick" => {
+                    if args.len() != 1 {
+                        print_error(&format!("'{inst}' instruction requires 1 argument, got {}", args.len()), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    let n = match args[0].parse::<u32>() {
+                        Ok(n) => n,
+                        Err(_) => {
+                            print_error(&format!("Couldn't parse '{}' as a stack depth (it must be a non-negative integer)", args[0]), line, i);
+
+                            had_error = true;
+                            break
+                        }
+                    };
+
+                    if inst == "drop" {
+                        push_node!(AstNodeData::Drop(n), nodes, line, i);
+                    }
+                    else {
+                        push_node!(AstNodeData::Pick(n), nodes, line, i);
+                    }
+                }
+
+                "strlen" => push_node!(AstNodeData::Strlen, nodes, line, i),
+                "substr" => push_node!(AstNodeData::Substr, nodes, line, i),
+                "charat" => push_node!(AstNodeData::Charat, nodes, line, i),
+                "indexof" => push_node!(AstNodeData::Indexof, nodes, line, i),
+                "split" => push_node!(AstNodeData::Split, nodes, line, i),
+                "join" => push_node!(AstNodeData::Join, nodes, line, i),
+                "upper" => push_node!(AstNodeData::Upper, nodes, line, i),
+                "lower" => push_node!(AstNodeData::Lower, nodes, line, i),
+                "trim" => push_node!(AstNodeData::Trim, nodes, line, i),
+                "replace" => push_node!(AstNodeData::Replace, nodes, line, i),
+                "startswith" => push_node!(AstNodeData::Startswith, nodes, line, i),
+                "endswith" => push_node!(AstNodeData::Endswith, nodes, line, i),
+
+                "tonum" => push_node!(AstNodeData::Tonum, nodes, line, i),
+                "tostr" => push_node!(AstNodeData::Tostr, nodes, line, i),
+                "tobool" => push_node!(AstNodeData::Tobool, nodes, line, i),
+                "tolabel" => push_node!(AstNodeData::Tolabel, nodes, line, i),
+                "typeof" => push_node!(AstNodeData::Typeof, nodes, line, i),
+
+                "listnew" => push_node!(AstNodeData::Listnew, nodes, line, i),
+                "listpush" => push_node!(AstNodeData::Listpush, nodes, line, i),
+                "listpop" => push_node!(AstNodeData::Listpop, nodes, line, i),
+                "listget" => push_node!(AstNodeData::Listget, nodes, line, i),
+                "listset" => push_node!(AstNodeData::Listset, nodes, line, i),
+                "listlen" => push_node!(AstNodeData::Listlen, nodes, line, i),
+                "listslice" => push_node!(AstNodeData::Listslice, nodes, line, i),
+                "listunpack" => push_node!(AstNodeData::Listunpack, nodes, line, i),
+
+                "mapnew" => push_node!(AstNodeData::Mapnew, nodes, line, i),
+                "mapget" => push_node!(AstNodeData::Mapget, nodes, line, i),
+                "mapset" => push_node!(AstNodeData::Mapset, nodes, line, i),
+                "maphas" => push_node!(AstNodeData::Maphas, nodes, line, i),
+                "mapdel" => push_node!(AstNodeData::Mapdel, nodes, line, i),
+                "mapkeys" => push_node!(AstNodeData::Mapkeys, nodes, line, i),
+                "maplen" => push_node!(AstNodeData::Maplen, nodes, line, i),
+
+                "ref" => {
+                    if args.len() != 1 {
+                        print_error(&format!("'ref' instruction requires 1 argument, got {}", args.len()), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if !is_identifier(args[0]) {
+                        print_error(&format!("Identifier '{}' is not valid", args[0]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    push_node!(AstNodeData::Ref(var(&mut slots, args[0])), nodes, line, i);
+                }
+                "load" => push_node!(AstNodeData::Load, nodes, line, i),
+                "store" => push_node!(AstNodeData::Store, nodes, line, i),
+
+                "copy" => push_node!(AstNodeData::Copy, nodes, line, i),
+
+                "isnil" => push_node!(AstNodeData::Isnil, nodes, line, i),
+
+                "new" | "getf" | "setf" => {
+                    if args.len() != 1 {
+                        print_error(&format!("'{inst}' instruction requires 1 argument, got {}", args.len()), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    if !is_identifier(args[0]) {
+                        print_error(&format!("Identifier '{}' is not valid", args[0]), line, i);
+
+                        had_error = true;
+                        break;
+                    }
+
+                    match inst {
+                        "new" => push_node!(AstNodeData::New(args[0].to_string().into()), nodes, line, i),
+                        "getf" => push_node!(AstNodeData::Getf(args[0].to_string().into()), nodes, line, i),
+                        _ => push_node!(AstNodeData::Setf(args[0].to_string().into()), nodes, line, i),
+                    }
+                }
                 
                 _ => {
                     print_error(&format!("Invalid instruction: '{inst}'"), line, i);
@@ -192,10 +586,41 @@ pub fn parse_value(s: &str, code: &str, line: usize) -> Option<Value> {
     Some(Value::Bool(s == "true"))
   }
 
+  else if s == "nil" {
+    Some(Value::Nil)
+  }
+
   else if s.starts_with('\"') && s.ends_with('\"') {
     Some(Value::Str(s[1..s.len() - 1].into()))
   }
 
+  else if s.starts_with('[') && s.ends_with(']') {
+    let mut items = vec![];
+
+    for item in split_literal_items(&s[1..s.len() - 1]) {
+      items.push(parse_value(item, code, line)?);
+    }
+
+    Some(Value::List(Rc::new(RefCell::new(items))))
+  }
+
+  else if s.starts_with('{') && s.ends_with('}') {
+    let mut entries = BTreeMap::new();
+
+    for entry in split_literal_items(&s[1..s.len() - 1]) {
+      let (key, value) = split_map_entry(entry)?;
+
+      let key = match parse_value(key, code, line)? {
+        Value::Str(k) => k.to_string(),
+        _ => return None,
+      };
+
+      entries.insert(key, parse_value(value, code, line)?);
+    }
+
+    Some(Value::Map(Rc::new(RefCell::new(entries))))
+  }
+
   else if s.starts_with('#') {
     if !is_label(s) {
       print_error(&format!("Label identifier '{}' is not valid", s), code, line);
@@ -213,101 +638,370 @@ pub fn parse_value(s: &str, code: &str, line: usize) -> Option<Value> {
   }
 }
 
+/// Gives every variable name a slot, in the order they first appear.
+fn var(slots: &mut HashMap<String, u32>, name: &str) -> Var {
+  let next = slots.len() as u32;
+  let slot = *slots.entry(name.into()).or_insert(next);
+
+  Var { name: name.into(), slot }
+}
+
 // ---
 
-pub fn parse_reduced(bytes: &[u8]) -> Result<Vec<ReducedAstNode>, ()> {
-    let mut nodes = vec![];
+/// A loaded bytecode file: its sections, decoded up front, and its instructions, which are only
+/// decoded when asked for. Names are borrowed from the bytes instead of being copied.
+pub struct Bytecode<'a> {
+    bytes: &'a [u8],
+    strings: Vec<&'a str>,
+    symbols: Vec<Rc<str>>,
+    constants: Vec<Value>,
+    declarations: Vec<usize>, // where each label and struct is
+    pub code: usize, // where the first instruction starts
+}
 
-    let mut count: usize = 0;
-    while count < bytes.len() {
-        let inst = bytes[count];
-        count += 1;
+impl<'a> Bytecode<'a> {
+    pub fn load(bytes: &'a [u8]) -> Result<Self, ()> {
+        let mut count: usize = 0;
+
+        // the string section holds every name and the contents of every string constant, once
+        let string_count = parse_varint!(bytes, &mut count, "string section");
+        let mut strings: Vec<&'a str> = Vec::with_capacity(string_count as usize);
+
+        for _ in 0..string_count {
+            match parse_raw_string(bytes, &mut count) {
+                Some(s) => strings.push(s),
+                None => {
+                    print_error_reduced("While parsing 'string section' instruction: Bytecode size isn't long enough to properly parse a string, or it isn't valid UTF-8", count);
+                    return Err(());
+                }
+            }
+        }
+
+        // the symbol section holds the name of every variable slot
+        let symbol_count = parse_varint!(bytes, &mut count, "symbol section");
+        let mut symbols = Vec::with_capacity(symbol_count as usize);
+
+        for _ in 0..symbol_count {
+            symbols.push(Rc::<str>::from(parse_string!(bytes, &mut count, &strings, "symbol section")));
+        }
+
+        // the constant section holds every constant, which instructions share instead of copying
+        let constant_count = parse_varint!(bytes, &mut count, "constant section");
+        let mut constants = Vec::with_capacity(constant_count as usize);
+
+        for _ in 0..constant_count {
+            match parse_value_reduced(bytes, &mut count, &strings) {
+                Some(value) => constants.push(value),
+                None => {
+                    print_error_reduced("While parsing 'constant section' instruction: Bytecode size isn't long enough to properly parse a value", count);
+                    return Err(());
+                }
+            }
+        }
+
+        // the declaration section holds where every label and struct is, relative to the first
+        // instruction, so that running doesn't have to go through the whole program to find them
+        let declaration_count = parse_varint!(bytes, &mut count, "declaration section");
+        let mut declarations = Vec::with_capacity(declaration_count as usize);
+
+        for _ in 0..declaration_count {
+            declarations.push(parse_varint!(bytes, &mut count, "declaration section") as usize);
+        }
+
+        declarations.iter_mut().for_each(|offset| *offset += count);
+
+        Ok(Self { bytes, strings, symbols, constants, declarations, code: count })
+    }
+
+    /// Where every label and struct is, and how many variable slots there are.
+    pub fn declarations(&self) -> (&[usize], usize) {
+        (&self.declarations, self.symbols.len())
+    }
+
+    /// Decodes the instruction at `count`, leaving `count` at the next one.
+    pub fn decode(&self, count: &mut usize) -> Result<AstNodeData<'a>, ()> {
+        let bytes = self.bytes;
+        let inst = bytes[*count];
+        *count += 1;
 
         match inst {
-            0 => nodes.push(ReducedAstNode(AstNodeData::Label(parse_string!(bytes, &mut count, "label")))),
-            1 => nodes.push(ReducedAstNode(AstNodeData::Pushc(parse_value!(bytes, &mut count, "pushc")))),
-            2 => nodes.push(ReducedAstNode(AstNodeData::Pushv(parse_string!(bytes, &mut count, "label")))),
+            0 => Ok(AstNodeData::Label(Cow::Borrowed(parse_string!(bytes, count, &self.strings, "label")))),
+            1 => Ok(AstNodeData::Pushc(parse_constant!(bytes, count, &self.constants, "pushc"))),
+            2 => Ok(AstNodeData::Pushv(parse_var!(bytes, count, &self.symbols, "pushv"))),
 
             3 => { // Setc
-                let value = parse_value!(bytes, &mut count, "setc");
-                let name = parse_string!(bytes, &mut count, "setc");
+                let value = parse_constant!(bytes, count, &self.constants, "setc");
+                let var = parse_var!(bytes, count, &self.symbols, "setc");
 
-                nodes.push(ReducedAstNode(AstNodeData::Setc(name, value)));
+                Ok(AstNodeData::Setc(var, value))
             }
 
-            4 => nodes.push(ReducedAstNode(AstNodeData::Popv(parse_string!(bytes, &mut count, "popv")))),
+            4 => Ok(AstNodeData::Popv(parse_var!(bytes, count, &self.symbols, "popv"))),
+
+            5 => Ok(AstNodeData::Pop),
+
+            6 => Ok(AstNodeData::Add),
+            7 => Ok(AstNodeData::Sub),
+            8 => Ok(AstNodeData::Mul),
+            9 => Ok(AstNodeData::Div),
+
+            10 => Ok(AstNodeData::Inc),
+            11 => Ok(AstNodeData::Dec),
+
+            12 => Ok(AstNodeData::Inputn),
+            13 => Ok(AstNodeData::Inputb),
+            14 => Ok(AstNodeData::Inputs),
+
+            15 => Ok(AstNodeData::Print),
+            16 => Ok(AstNodeData::Println),
+
+            17 => Ok(AstNodeData::Cmpg),
+            18 => Ok(AstNodeData::Cmpge),
+
+            19 => Ok(AstNodeData::Cmpl),
+            20 => Ok(AstNodeData::Cmple),
+
+            21 => Ok(AstNodeData::Cmpe),
+            22 => Ok(AstNodeData::Cmpne),
+
+            23 => Ok(AstNodeData::Jmp),
+            24 => Ok(AstNodeData::Jt),
+            25 => Ok(AstNodeData::Jf),
+
+            26 => Ok(AstNodeData::Save),
+            27 => Ok(AstNodeData::Ret),
+
+            28 => Ok(AstNodeData::Mod),
+            29 => Ok(AstNodeData::Pow),
+            30 => Ok(AstNodeData::Neg),
+            31 => Ok(AstNodeData::Abs),
+            32 => Ok(AstNodeData::Min),
+            33 => Ok(AstNodeData::Max),
+
+            34 => Ok(AstNodeData::Floor),
+            35 => Ok(AstNodeData::Ceil),
+            36 => Ok(AstNodeData::Round),
+            37 => Ok(AstNodeData::Sqrt),
+
+            38 => Ok(AstNodeData::Land),
+            39 => Ok(AstNodeData::Lor),
+            40 => Ok(AstNodeData::Lnot),
+            41 => Ok(AstNodeData::Lxor),
+
+            42 => Ok(AstNodeData::Dup),
+            43 => Ok(AstNodeData::Swap),
+            44 => Ok(AstNodeData::Over),
+            45 => Ok(AstNodeData::Rot),
+            46 => Ok(AstNodeData::Drop(parse_varint!(bytes, count, "drop"))),
+            47 => Ok(AstNodeData::Pick(parse_varint!(bytes, count, "pick"))),
+
+            48 => Ok(AstNodeData::Strlen),
+            49 => Ok(AstNodeData::Substr),
+            50 => Ok(AstNodeData::Charat),
+            51 => Ok(AstNodeData::Indexof),
+            52 => Ok(AstNodeData::Split),
+            53 => Ok(AstNodeData::Join),
+            54 => Ok(AstNodeData::Upper),
+            55 => Ok(AstNodeData::Lower),
+            56 => Ok(AstNodeData::Trim),
+            57 => Ok(AstNodeData::Replace),
+            58 => Ok(AstNodeData::Startswith),
+            59 => Ok(AstNodeData::Endswith),
+
+            60 => Ok(AstNodeData::Tonum),
+            61 => Ok(AstNodeData::Tostr),
+            62 => Ok(AstNodeData::Tobool),
+            63 => Ok(AstNodeData::Tolabel),
+            64 => Ok(AstNodeData::Typeof),
+
+            65 => Ok(AstNodeData::Listnew),
+            66 => Ok(AstNodeData::Listpush),
+            67 => Ok(AstNodeData::Listpop),
+            68 => Ok(AstNodeData::Listget),
+            69 => Ok(AstNodeData::Listset),
+            70 => Ok(AstNodeData::Listlen),
+            71 => Ok(AstNodeData::Listslice),
+            72 => Ok(AstNodeData::Listunpack),
+
+            73 => Ok(AstNodeData::Mapnew),
+            74 => Ok(AstNodeData::Mapget),
+            75 => Ok(AstNodeData::Mapset),
+            76 => Ok(AstNodeData::Maphas),
+            77 => Ok(AstNodeData::Mapdel),
+            78 => Ok(AstNodeData::Mapkeys),
+            79 => Ok(AstNodeData::Maplen),
+
+            80 => Ok(AstNodeData::Ref(parse_var!(bytes, count, &self.symbols, "ref"))),
+            81 => Ok(AstNodeData::Load),
+            82 => Ok(AstNodeData::Store),
+
+            83 => Ok(AstNodeData::Copy),
+
+            84 => Ok(AstNodeData::Isnil),
+
+            85 => { // Struct
+                let name = Cow::Borrowed(parse_string!(bytes, count, &self.strings, "struct"));
+                let len = parse_varint!(bytes, count, "struct");
+                let mut fields = vec![];
+
+                for _ in 0..len {
+                    let field = Cow::Borrowed(parse_string!(bytes, count, &self.strings, "struct"));
+                    let ty = parse_type!(bytes, count, &self.strings, "struct");
+
+                    fields.push((field, ty));
+                }
 
-            5 => nodes.push(ReducedAstNode(AstNodeData::Pop)),
+                Ok(AstNodeData::Struct(name, fields))
+            }
+            86 => Ok(AstNodeData::New(Cow::Borrowed(parse_string!(bytes, count, &self.strings, "new")))),
+            87 => Ok(AstNodeData::Getf(Cow::Borrowed(parse_string!(bytes, count, &self.strings, "getf")))),
+            88 => Ok(AstNodeData::Setf(Cow::Borrowed(parse_string!(bytes, count, &self.strings, "setf")))),
+
+            89 => { // Decl
+                let var = parse_var!(bytes, count, &self.symbols, "decl");
+                let ty = parse_type!(bytes, count, &self.strings, "decl");
 
-            6 => nodes.push(ReducedAstNode(AstNodeData::Add)),
-            7 => nodes.push(ReducedAstNode(AstNodeData::Sub)),
-            8 => nodes.push(ReducedAstNode(AstNodeData::Mul)),
-            9 => nodes.push(ReducedAstNode(AstNodeData::Div)),
+                Ok(AstNodeData::Decl(var, ty))
+            }
+            90 => { // Const
+                let value = parse_constant!(bytes, count, &self.constants, "const");
+                let var = parse_var!(bytes, count, &self.symbols, "const");
+
+                Ok(AstNodeData::Const(var, value))
+            }
 
-            10 => nodes.push(ReducedAstNode(AstNodeData::Inc)),
-            11 => nodes.push(ReducedAstNode(AstNodeData::Dec)),
+            98 => Ok(AstNodeData::Incv(parse_var!(bytes, count, &self.symbols, "incv"))),
+            99 => Ok(AstNodeData::Decv(parse_var!(bytes, count, &self.symbols, "decv"))),
+            100 => { // Jcmp
+                let cmp = match bytes.get(*count).copied().and_then(Comparison::from_opcode) {
+                    Some(cmp) => cmp,
+                    None => {
+                        print_error_reduced("While parsing 'jcmp' instruction: Bytecode size isn't long enough to properly parse a comparison, or it isn't valid", *count);
+                        return Err(());
+                    }
+                };
 
-            12 => nodes.push(ReducedAstNode(AstNodeData::Inputn)),
-            13 => nodes.push(ReducedAstNode(AstNodeData::Inputb)),
-            14 => nodes.push(ReducedAstNode(AstNodeData::Inputs)),
+                *count += 1;
 
-            15 => nodes.push(ReducedAstNode(AstNodeData::Print)),
-            16 => nodes.push(ReducedAstNode(AstNodeData::Println)),
+                let var = parse_var!(bytes, count, &self.symbols, "jcmp");
+                let value = parse_constant!(bytes, count, &self.constants, "jcmp");
+                let label = Cow::Borrowed(parse_string!(bytes, count, &self.strings, "jcmp"));
 
-            17 => nodes.push(ReducedAstNode(AstNodeData::Cmpg)),
-            18 => nodes.push(ReducedAstNode(AstNodeData::Cmpge)),
+                Ok(AstNodeData::Jcmp(cmp, var, value, label))
+            }
 
-            19 => nodes.push(ReducedAstNode(AstNodeData::Cmpl)),
-            20 => nodes.push(ReducedAstNode(AstNodeData::Cmple)),
+            PUSHC_FALSE => Ok(AstNodeData::Pushc(Value::Bool(false))),
+            PUSHC_TRUE => Ok(AstNodeData::Pushc(Value::Bool(true))),
+            PUSHC_NIL => Ok(AstNodeData::Pushc(Value::Nil)),
+            PUSHC_INT => Ok(AstNodeData::Pushc(Value::Num(parse_zigzag!(bytes, count, "pushc")))),
 
-            21 => nodes.push(ReducedAstNode(AstNodeData::Cmpe)),
-            22 => nodes.push(ReducedAstNode(AstNodeData::Cmpne)),
+            SETC_FALSE | SETC_TRUE => {
+                let var = parse_var!(bytes, count, &self.symbols, "setc");
+                Ok(AstNodeData::Setc(var, Value::Bool(inst == SETC_TRUE)))
+            }
 
-            23 => nodes.push(ReducedAstNode(AstNodeData::Jmp)),
-            24 => nodes.push(ReducedAstNode(AstNodeData::Jt)),
-            25 => nodes.push(ReducedAstNode(AstNodeData::Jf)),
+            SETC_INT => {
+                let value = Value::Num(parse_zigzag!(bytes, count, "setc"));
+                let var = parse_var!(bytes, count, &self.symbols, "setc");
 
-            26 => nodes.push(ReducedAstNode(AstNodeData::Save)),
-            27 => nodes.push(ReducedAstNode(AstNodeData::Ret)),
+                Ok(AstNodeData::Setc(var, value))
+            }
+
+            PUSHC_SMALL.. => Ok(AstNodeData::Pushc(Value::Num((inst - PUSHC_SMALL) as f64))),
 
             _ => {
-                print_error_reduced(&format!("Invalid instruction code: {}", inst), count);
-                return Err(());
+                print_error_reduced(&format!("Invalid instruction code: {}", inst), *count);
+                Err(())
             }
         }
     }
-    
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+pub fn parse_reduced(bytes: &[u8]) -> Result<Vec<ReducedAstNode<'_>>, ()> {
+    let bytecode = Bytecode::load(bytes)?;
+    let mut nodes = vec![];
+    let mut count = bytecode.code;
+
+    while count < bytes.len() {
+        nodes.push(ReducedAstNode(bytecode.decode(&mut count)?));
+    }
+
     Ok(nodes)
 }
 
-fn parse_string(slice: &[u8], count: &mut usize) -> Option<String> {
+fn parse_raw_string<'a>(slice: &'a [u8], count: &mut usize) -> Option<&'a str> {
     let mut c = *count;
+    let len = parse_varint(slice, &mut c)? as usize;
 
-    if slice.len() - c >= 4 {
-        let len_bytes: [u8; 4] = slice[c..(c + 4)].try_into().unwrap();
-        let len = u32::from_ne_bytes(len_bytes) as usize;
+    if slice.len() - c >= len {
+        let data = std::str::from_utf8(&slice[c..(c + len)]).ok()?;
 
-        c += 4;
-        *count += 4;
+        *count = c + len;
+        Some(data)
+    }
+    else {
+        None
+    }
+}
 
-        if slice.len() - c >= len {
-            let data = String::from_utf8_lossy(&slice[c..(c + len)]).into();
+/// Reads a LEB128 integer, as written by `compiler::encode_varint`.
+fn parse_varint(slice: &[u8], count: &mut usize) -> Option<u32> {
+    let mut n: u32 = 0;
 
-            *count += len;
-            Some(data)
+    for (i, byte) in slice[*count..].iter().take(5).enumerate() {
+        // the fifth byte only has room for the 4 highest bits
+        if i == 4 && *byte > 0x0f {
+            return None;
         }
-        else {
-            None
+
+        n |= ((byte & 0x7f) as u32) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            *count += i + 1;
+            return Some(n);
         }
     }
-    else {
-        None
+
+    None
+}
+
+fn parse_zigzag(slice: &[u8], count: &mut usize) -> Option<f64> {
+    let n = parse_varint(slice, count)?;
+    Some(((n >> 1) as i32 ^ -((n & 1) as i32)) as f64)
+}
+
+fn parse_string<'a>(slice: &[u8], count: &mut usize, strings: &[&'a str]) -> Option<&'a str> {
+    let index = parse_varint(slice, count)?;
+    strings.get(index as usize).copied()
+}
+
+fn parse_type_reduced(slice: &[u8], count: &mut usize, strings: &[&str]) -> Option<Type> {
+    let kind = *slice.get(*count)?;
+    *count += 1;
+
+    match kind {
+        0 => Some(Type::Num),
+        1 => Some(Type::Str),
+        2 => Some(Type::Bool),
+        3 => Some(Type::Label),
+        4 => Some(Type::List),
+        5 => Some(Type::Map),
+        6 => Some(Type::Ref),
+        7 => Some(Type::Nil),
+        8 => Some(Type::Record),
+        9 => Some(Type::Any),
+        10 => Some(Type::Struct(parse_string(slice, count, strings)?.to_string())),
+        _ => None
     }
 }
 
-fn parse_value_reduced(slice: &[u8], count: &mut usize) -> Option<Value> {
+fn parse_value_reduced(slice: &[u8], count: &mut usize, strings: &[&str]) -> Option<Value> {
     let mut c = *count;
-    let kind = slice[c];
+    let kind = *slice.get(c)?;
 
     c += 1;
     *count += 1;
@@ -326,28 +1020,81 @@ fn parse_value_reduced(slice: &[u8], count: &mut usize) -> Option<Value> {
             }
         }
 
-        1 => { // Str
-            let s = parse_string(slice, count)?;
-            Some(Value::Str(s))
-        }
+        1 => Some(Value::Str(parse_string(slice, count, strings)?.into())),
 
         2 => { // Bool
-            if slice.len() >= 2 {
-                let value = slice[c] != 0;
+            let value = *slice.get(c)? != 0;
 
-                *count += 1;
-                Some(Value::Bool(value))
-            }
-            else {
-                None
+            *count += 1;
+            Some(Value::Bool(value))
+        }
+
+        3 => Some(Value::Label(parse_string(slice, count, strings)?.into())),
+
+        4 => { // List
+            let len = parse_varint(slice, count)?;
+            let mut items = vec![];
+
+            for _ in 0..len {
+                items.push(parse_value_reduced(slice, count, strings)?);
             }
+
+            Some(Value::List(Rc::new(RefCell::new(items))))
         }
 
-        3 => { // Label
-            let s = parse_string(slice, count)?;
-            Some(Value::Label(s))
+        5 => { // Map
+            let len = parse_varint(slice, count)?;
+            let mut entries = BTreeMap::new();
+
+            for _ in 0..len {
+                let key = parse_string(slice, count, strings)?.to_string();
+                entries.insert(key, parse_value_reduced(slice, count, strings)?);
+            }
+
+            Some(Value::Map(Rc::new(RefCell::new(entries))))
         }
 
+        7 => Some(Value::Nil),
+
         _ => None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_value_reduced};
+    use crate::ast::{AstNodeData, Type, Value};
+
+    #[test]
+    fn rejects_structs_named_like_builtin_types() {
+        for name in ["num", "str", "bool", "label", "list", "map", "ref", "nil", "record", "any"] {
+            assert!(parse(&format!(".struct {name} x:num\n")).is_err(), "{name}");
+        }
+
+        assert!(parse(".struct Point x:num\n").is_ok());
+    }
+
+    #[test]
+    fn parses_the_record_type() {
+        let ast = parse(".struct Line from:record\ndecl r record\n").unwrap();
+
+        match (&ast[0].data, &ast[1].data) {
+            (AstNodeData::Struct(_, fields), AstNodeData::Decl(_, ty)) => {
+                assert_eq!(fields[0].1, Type::Record);
+                assert_eq!(*ty, Type::Record);
+            }
+            (a, b) => panic!("parsed as '{}' and '{}'", a.name(), b.name()),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_bool_constants() {
+        // a bool tag at the end of the section, with nothing after it
+        let mut count = 2;
+        assert!(parse_value_reduced(&[0, 0, 2], &mut count, &[]).is_none());
+
+        let mut count = 0;
+        assert!(matches!(parse_value_reduced(&[2, 1], &mut count, &[]), Some(Value::Bool(true))));
+        assert_eq!(count, 2);
+    }
+}
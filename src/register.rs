@@ -0,0 +1,344 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{ast::{AstNodeData, Comparison, Value, Var}, heap::HeapStats, interpreter::{self, Machine, Options, Program}, util::print_error_reduced};
+
+/// Where an instruction reads an operand from.
+#[derive(Debug)]
+pub enum Operand {
+  /// Popped from the operation stack.
+  Stack,
+  /// Read from a variable, by the `pushv` at the given position.
+  Var(Var, usize),
+  /// A constant, pushed by `pushc`.
+  Const(Value),
+}
+
+/// Where an instruction writes its result to.
+#[derive(Debug)]
+pub enum Dest {
+  /// Pushed onto the operation stack.
+  Stack,
+  /// Assigned to a variable, by the `popv` at the given position.
+  Var(Var, usize),
+}
+
+/// An instruction of the register-based bytecode. Variables are the registers: instead of
+/// going through the operation stack, instructions name the variables and constants they read
+/// and the variable they write. Positions are the ones of the stack instructions they were
+/// translated from, so errors point to the same places.
+#[derive(Debug)]
+pub enum Instruction<'a> {
+  /// Pushes a value onto the stack, or assigns it to a variable.
+  Move { src: Operand, dst: Dest },
+  /// An arithmetic, comparison or logical instruction, where `a` is the operand that was on top.
+  Binary { op: AstNodeData<'a>, a: Operand, b: Operand, dst: Dest, count: usize },
+  /// `inc` or `dec`.
+  Step { op: AstNodeData<'a>, src: Operand, dst: Dest, count: usize },
+  /// A comparison followed by `jt` (or `jf`, with `when` false) to a constant label.
+  Branch { cmp: Comparison, a: Operand, b: Operand, label: Rc<str>, target: Option<usize>, when: bool, count: usize, jump: usize },
+  /// `jmp` to a constant label.
+  Jump { label: Rc<str>, target: Option<usize>, count: usize },
+  /// Any other instruction, run the same way as by the stack-based interpreter.
+  Stack(AstNodeData<'a>, usize),
+}
+
+/// A program translated to the register-based bytecode.
+pub struct Registers<'a> {
+  pub code: Vec<Instruction<'a>>,
+  labels: interpreter::LabelMap, // to the instruction right after the label
+  structs: interpreter::StructMap,
+  slots: usize,
+}
+
+/// Translates a stack program, keeping the values it pushes with `pushc` and `pushv` aside
+/// instead of pushing them, until an instruction that can take them as operands uses them.
+///
+/// Only a run of `pushc` and `pushv` is kept aside, and only up to the next instruction, so
+/// variables are read in the same order and with nothing else happening in between. Whatever
+/// can't be used as operands is pushed before that instruction runs, which is also done before
+/// every label, so the stack is the same at every jump target.
+pub fn translate<P: Program + ?Sized>(program: &P) -> Result<Registers<'_>, ()> {
+  let (_, structs, slots) = interpreter::scan(program)?;
+
+  let mut code: Vec<Instruction> = vec![];
+  let mut labels = HashMap::new();
+  let mut pending: Vec<Operand> = vec![];
+  let mut boundary = 0; // instructions before it can't be merged with the next ones
+
+  let mut count = program.start();
+
+  while let Some((instruction, next)) = program.fetch(count)? {
+    match instruction.into_owned() {
+      AstNodeData::Label(name) => {
+        flush(&mut code, &mut pending, 0);
+        labels.insert(name.to_string(), code.len());
+        boundary = code.len();
+      }
+
+      AstNodeData::Pushc(value) => pending.push(Operand::Const(value)),
+      AstNodeData::Pushv(var) => pending.push(Operand::Var(var, count)),
+
+      AstNodeData::Popv(var) => {
+        let dst = Dest::Var(var, count);
+        let mergeable = code.len() > boundary;
+
+        match pending.pop() {
+          Some(src) => {
+            // the variable may be one of the values kept aside, which must be read first
+            flush(&mut code, &mut pending, 0);
+            code.push(Instruction::Move { src, dst });
+          }
+          None => match code.last_mut() {
+            Some(Instruction::Move { dst: last @ Dest::Stack, .. }
+              | Instruction::Binary { dst: last @ Dest::Stack, .. }
+              | Instruction::Step { dst: last @ Dest::Stack, .. }) if mergeable => *last = dst,
+            _ => code.push(Instruction::Move { src: Operand::Stack, dst }),
+          },
+        }
+      }
+
+      op @ (AstNodeData::Add | AstNodeData::Sub | AstNodeData::Mul | AstNodeData::Div
+      | AstNodeData::Mod | AstNodeData::Pow | AstNodeData::Min | AstNodeData::Max
+      | AstNodeData::Cmpg | AstNodeData::Cmpge | AstNodeData::Cmpl | AstNodeData::Cmple | AstNodeData::Cmpe | AstNodeData::Cmpne
+      | AstNodeData::Land | AstNodeData::Lor | AstNodeData::Lxor) => {
+        flush(&mut code, &mut pending, 2);
+
+        let a = pending.pop().unwrap_or(Operand::Stack);
+        let b = pending.pop().unwrap_or(Operand::Stack);
+
+        code.push(Instruction::Binary { op, a, b, dst: Dest::Stack, count });
+      }
+
+      op @ (AstNodeData::Inc | AstNodeData::Dec) => {
+        flush(&mut code, &mut pending, 1);
+
+        let src = pending.pop().unwrap_or(Operand::Stack);
+        code.push(Instruction::Step { op, src, dst: Dest::Stack, count });
+      }
+
+      jump @ (AstNodeData::Jt | AstNodeData::Jf) => match pending.as_slice() {
+        [Operand::Const(Value::Label(_))] if code.len() > boundary && matches!(code.last(), Some(Instruction::Binary { dst: Dest::Stack, op, .. }) if Comparison::of(op).is_some()) => {
+          let Some(Operand::Const(Value::Label(label))) = pending.pop() else { unreachable!() };
+          let Some(Instruction::Binary { op, a, b, count: compared, .. }) = code.pop() else { unreachable!() };
+
+          let cmp = Comparison::of(&op).expect("checked to be a comparison");
+          let when = matches!(jump, AstNodeData::Jt);
+
+          code.push(Instruction::Branch { cmp, a, b, label, target: None, when, count: compared, jump: count });
+        }
+        _ => {
+          flush(&mut code, &mut pending, 0);
+          code.push(Instruction::Stack(jump, count));
+        }
+      },
+
+      AstNodeData::Jmp => match pending.last() {
+        Some(Operand::Const(Value::Label(_))) => {
+          let Some(Operand::Const(Value::Label(label))) = pending.pop() else { unreachable!() };
+
+          flush(&mut code, &mut pending, 0);
+          code.push(Instruction::Jump { label, target: None, count });
+        }
+        _ => {
+          flush(&mut code, &mut pending, 0);
+          code.push(Instruction::Stack(AstNodeData::Jmp, count));
+        }
+      },
+
+      instruction => {
+        flush(&mut code, &mut pending, 0);
+        code.push(Instruction::Stack(instruction, count));
+      }
+    }
+
+    count = next;
+  }
+
+  flush(&mut code, &mut pending, 0);
+
+  // every label is known now, including the ones after the jumps to them
+  for instruction in &mut code {
+    if let Instruction::Branch { label, target, .. } | Instruction::Jump { label, target, .. } = instruction {
+      *target = labels.get(&**label).copied();
+    }
+  }
+
+  Ok(Registers { code, labels, structs, slots })
+}
+
+/// Pushes the values kept aside onto the stack, except for the top `keep` ones.
+fn flush(code: &mut Vec<Instruction>, pending: &mut Vec<Operand>, keep: usize) {
+  let len = pending.len().saturating_sub(keep);
+
+  for src in pending.drain(..len) {
+    code.push(Instruction::Move { src, dst: Dest::Stack });
+  }
+}
+
+pub fn interpret(registers: &Registers, options: &Options) -> Result<HeapStats, ()> {
+  let mut machine = Machine::new(registers.labels.clone(), registers.structs.clone(), registers.slots, options);
+  let code = &registers.code;
+
+  let mut pc = 0;
+
+  while let Some(instruction) = code.get(pc) {
+    machine.collect();
+    pc += 1;
+
+    match instruction {
+      Instruction::Move { src, dst } => {
+        // only `popv` takes a value from the stack
+        let count = if let Dest::Var(_, count) = dst { *count } else { 0 };
+
+        let value = read(&mut machine, src)?;
+        let value = take(&mut machine, value, "popv", count)?;
+
+        write(&mut machine, dst, value)?;
+      }
+
+      Instruction::Binary { op, a, b, dst, count } => {
+        let value = binary(&mut machine, op, a, b, *count)?;
+        write(&mut machine, dst, value)?;
+      }
+
+      Instruction::Step { op, src, dst, count } => {
+        if let Some(Value::Num(n)) = peek(&machine, src) {
+          let value = Value::Num(if let AstNodeData::Inc = op { n + 1.0 } else { n - 1.0 });
+          write(&mut machine, dst, value)?;
+          continue;
+        }
+
+        let x = read(&mut machine, src)?;
+        let x = take(&mut machine, x, op.name(), *count)?;
+
+        let value = match (op, x) {
+          (AstNodeData::Inc, Value::Num(n)) => Value::Num(n + 1.0),
+          (AstNodeData::Dec, Value::Num(n)) => Value::Num(n - 1.0),
+          (AstNodeData::Inc, x) => {
+            print_error_reduced(&format!("In 'inc' instruction: Cannot increment {}", x.as_str_debug()), *count);
+            return Err(());
+          }
+          (_, x) => {
+            print_error_reduced(&format!("In 'dec' instruction: Cannot decrement {}", x.as_str_debug()), *count);
+            return Err(());
+          }
+        };
+
+        write(&mut machine, dst, value)?;
+      }
+
+      Instruction::Branch { cmp, a, b, label, target, when, count, jump } => {
+        let result = binary(&mut machine, &cmp.instruction(), a, b, *count)?;
+
+        let target = match target {
+          Some(t) => *t,
+          None => {
+            let inst = if *when { "jt" } else { "jf" };
+            print_error_reduced(&format!("In '{}' instruction: Label {} doesn't exist", inst, Value::Label(label.clone()).as_str_debug()), *jump);
+            return Err(());
+          }
+        };
+
+        // comparisons always give a boolean
+        if let Value::Bool(b) = result {
+          if b == *when {
+            pc = target;
+          }
+        }
+      }
+
+      Instruction::Jump { label, target, count } => match target {
+        Some(t) => pc = *t,
+        None => {
+          print_error_reduced(&format!("In 'jmp' instruction: Label {} doesn't exist", label), *count);
+          return Err(());
+        }
+      },
+
+      Instruction::Stack(instruction, count) => if let Some(target) = machine.execute(instruction, *count)? {
+        pc = target;
+      },
+    }
+  }
+
+  Ok(machine.heap.stats())
+}
+
+fn binary(machine: &mut Machine, op: &AstNodeData, a: &Operand, b: &Operand, count: usize) -> Result<Value, ()> {
+  if let (Some(x), Some(y)) = (peek(machine, a), peek(machine, b)) {
+    if let Ok(v) = machine.operators.apply(op.discriminant(), x, y) {
+      return Ok(v);
+    }
+  }
+
+  // anything else, including errors, goes the same way as through the stack
+  let inst = op.name();
+
+  // `b` was pushed first, and `a` is the first one popped
+  let b = read(machine, b)?;
+  let a = read(machine, a)?;
+
+  let a = take(machine, a, inst, count)?;
+  let b = take(machine, b, inst, count)?;
+
+  match machine.operators.apply(op.discriminant(), &a, &b) {
+    Ok(v) => Ok(v),
+    Err(e) => {
+      print_error_reduced(&format!("In '{}' instruction: {}", inst, e), count);
+      Err(())
+    }
+  }
+}
+
+/// Borrows an operand if that's all it takes to use it, without cloning or instantiating it.
+fn peek<'m>(machine: &'m Machine, operand: &'m Operand) -> Option<&'m Value> {
+  match operand {
+    Operand::Var(var, _) => machine.scopes.get(var.slot).and_then(|v| v.value.as_ref()),
+    Operand::Const(Value::List(_) | Value::Map(_)) | Operand::Stack => None,
+    Operand::Const(value) => Some(value),
+  }
+}
+
+/// Reads an operand, or gives `None` if it's on the stack, to be popped later by `take`.
+fn read(machine: &mut Machine, operand: &Operand) -> Result<Option<Value>, ()> {
+  match operand {
+    Operand::Stack => Ok(None),
+    Operand::Const(value) => Ok(Some(machine.heap.instantiate(value))),
+
+    Operand::Var(var, count) => match machine.scopes.get(var.slot).map(|v| &v.value) {
+      Some(Some(value)) => Ok(Some(value.clone())),
+      _ if machine.options.undefined_as_nil => Ok(Some(Value::Nil)),
+      Some(None) => {
+        print_error_reduced(&format!("In 'pushv' instruction: Variable '{}' was declared but never assigned", var.name), *count);
+        Err(())
+      }
+      None => {
+        print_error_reduced(&format!("In 'pushv' instruction: Variable '{}' doesn't exist", var.name), *count);
+        Err(())
+      }
+    },
+  }
+}
+
+/// Takes an operand given by `read`, popping it if it's on the stack.
+fn take(machine: &mut Machine, value: Option<Value>, inst: &str, count: usize) -> Result<Value, ()> {
+  match value.or_else(|| machine.operation_stack.pop()) {
+    Some(v) => Ok(v),
+    None => {
+      print_error_reduced(&format!("In '{}' instruction: Attempt to pop the operation stack while being empty", inst), count);
+      Err(())
+    }
+  }
+}
+
+fn write(machine: &mut Machine, dst: &Dest, value: Value) -> Result<(), ()> {
+  match dst {
+    Dest::Stack => machine.operation_stack.push(value),
+    Dest::Var(var, count) => if let Err(e) = machine.scopes.assign(machine.scopes.depth(), var, value, machine.options.strict) {
+      print_error_reduced(&format!("In 'popv' instruction: {}", e), *count);
+      return Err(());
+    },
+  }
+
+  Ok(())
+}
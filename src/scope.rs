@@ -0,0 +1,159 @@
+use crate::ast::{Type, Value, Var};
+
+#[derive(Debug, Clone)]
+pub struct Variable {
+  pub value: Option<Value>, // `None` if declared with 'decl' and never assigned
+  pub ty: Option<Type>, // `Some` if declared with 'decl' or 'const'
+  pub constant: bool,
+}
+
+#[derive(Debug)]
+struct Binding {
+  depth: usize, // of the scope the variable lives in
+  var: Variable,
+}
+
+/// The variables of every scope that hasn't been popped by `ret`.
+///
+/// Instead of a map per scope, every slot has a stack of bindings, one for each scope that
+/// has the variable, with the innermost on top. Reading a variable takes the top binding,
+/// which is the same one a search from the current scope outwards would find. Entering a
+/// scope copies nothing, and leaving it only removes the bindings it created.
+pub struct Scopes {
+  bindings: Vec<Vec<Binding>>, // indexed by slot, sorted by depth
+  created: Vec<Vec<u32>>, // slots bound in each scope, indexed by depth; kept when leaving, to be reused
+  ids: Vec<usize>, // id of each scope, indexed by depth; always increasing
+  next_id: usize,
+}
+
+impl Scopes {
+  pub fn new(slots: usize) -> Self {
+    Self {
+      bindings: (0..slots).map(|_| vec![]).collect(),
+      created: vec![vec![]],
+      ids: vec![0],
+      next_id: 1,
+    }
+  }
+
+  /// Enters a new scope, for `save`.
+  pub fn enter(&mut self) {
+    if self.created.len() == self.ids.len() {
+      self.created.push(vec![]);
+    }
+
+    self.ids.push(self.next_id);
+    self.next_id += 1;
+  }
+
+  /// Leaves the current scope, for `ret`. Fails on the outermost scope.
+  pub fn exit(&mut self) -> Result<(), ()> {
+    if self.ids.len() == 1 {
+      return Err(());
+    }
+
+    let depth = self.depth();
+
+    // deeper scopes are already gone, so the bindings of this one are on top
+    for slot in self.created[depth].drain(..) {
+      self.bindings[slot as usize].pop();
+    }
+
+    self.ids.pop();
+    Ok(())
+  }
+
+  pub fn depth(&self) -> usize {
+    self.ids.len() - 1
+  }
+
+  /// Finds a variable in the current scope, or in the closest enclosing scope that has it.
+  pub fn get(&self, slot: u32) -> Option<&Variable> {
+    self.bindings[slot as usize].last().map(|b| &b.var)
+  }
+
+  /// Finds a variable in the scope at `depth` only.
+  pub fn get_at(&self, depth: usize, slot: u32) -> Option<&Variable> {
+    let bindings = &self.bindings[slot as usize];
+
+    bindings.binary_search_by_key(&depth, |b| b.depth).ok().map(|i| &bindings[i].var)
+  }
+
+  /// Finds the id of the scope a variable would be read from, the same way `get` does.
+  /// Variables that don't exist yet belong to the current scope.
+  pub fn find(&self, slot: u32) -> usize {
+    let depth = self.bindings[slot as usize].last().map_or(self.depth(), |b| b.depth);
+    self.ids[depth]
+  }
+
+  /// Finds the depth of the scope with the given id, if it hasn't been popped yet.
+  pub fn resolve(&self, id: usize) -> Option<usize> {
+    self.ids.binary_search(&id).ok()
+  }
+
+  /// Assigns a value to a variable of the scope at `depth`, respecting its declaration.
+  pub fn assign(&mut self, depth: usize, var: &Var, value: Value, strict: bool) -> Result<(), String> {
+    let name = &var.name;
+
+    match self.position(depth, var.slot) {
+      Ok(i) => {
+        let v = &mut self.bindings[var.slot as usize][i].var;
+
+        if v.constant {
+          return Err(format!("Cannot assign to '{}', since it is a constant", name));
+        }
+
+        if let Some(ty) = &v.ty {
+          if !ty.matches(&value) {
+            return Err(format!("Cannot assign {} to '{}', since it was declared as {}", value.as_str_debug(), name, ty.name()));
+          }
+        }
+
+        v.value = Some(value);
+        Ok(())
+      }
+
+      Err(_) if strict => Err(format!("Variable '{}' must be declared with 'decl' before being assigned", name)),
+
+      Err(i) => {
+        self.bind(depth, var.slot, i, Variable { value: Some(value), ty: None, constant: false });
+        Ok(())
+      }
+    }
+  }
+
+  /// Creates a variable in the current scope.
+  pub fn declare(&mut self, var: &Var, variable: Variable) -> Result<(), String> {
+    let depth = self.depth();
+
+    match self.position(depth, var.slot) {
+      Ok(_) => Err(format!("Variable '{}' already exists in this scope", var.name)),
+      Err(i) => {
+        self.bind(depth, var.slot, i, variable);
+        Ok(())
+      }
+    }
+  }
+
+  /// Every value held by a variable, for the collector.
+  pub fn values(&self) -> impl Iterator<Item = &Value> {
+    self.bindings.iter().flatten().filter_map(|b| b.var.value.as_ref())
+  }
+
+  fn position(&self, depth: usize, slot: u32) -> Result<usize, usize> {
+    let bindings = &self.bindings[slot as usize];
+
+    // most lookups are for the current scope, whose binding can only be the top one
+    match bindings.last() {
+      Some(b) if b.depth == depth => Ok(bindings.len() - 1),
+      Some(b) if b.depth < depth => Err(bindings.len()),
+      None => Err(0),
+      _ => bindings.binary_search_by_key(&depth, |b| b.depth),
+    }
+  }
+
+  fn bind(&mut self, depth: usize, slot: u32, index: usize, var: Variable) {
+    self.bindings[slot as usize].insert(index, Binding { depth, var });
+    self.created[depth].push(slot);
+  }
+}
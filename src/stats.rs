@@ -0,0 +1,110 @@
+use crate::{ast::{AstNode, AstNodeData, Type, Value}, compiler::{self, Pool}};
+
+struct Row {
+  name: &'static str,
+  count: usize,
+  old: usize,
+  compact: usize,
+}
+
+/// Prints how many bytes each kind of instruction takes in the bytecode, next to what it took in
+/// the format before varints and the string and constant sections, where every slot and count is
+/// 4 bytes and every name and constant is written in full where it's used.
+pub fn stats(ast: &[AstNode]) {
+  let mut rows: Vec<Row> = vec![];
+  let mut pool = Pool::default();
+
+  for node in ast {
+    let mut output = vec![];
+    compiler::encode_node(&mut output, &mut pool, &node.data);
+
+    let name = node.data.name();
+
+    let i = match rows.iter().position(|r| r.name == name) {
+      Some(i) => i,
+      None => {
+        rows.push(Row { name, count: 0, old: 0, compact: 0 });
+        rows.len() - 1
+      }
+    };
+
+    rows[i].count += 1;
+    rows[i].old += old_size(&node.data);
+    rows[i].compact += output.len();
+  }
+
+  rows.sort_by(|a, b| b.old.cmp(&a.old).then(a.name.cmp(b.name)));
+
+  let symbols = compiler::symbols(ast);
+  let compact = compiler::encode(ast).len();
+
+  // the symbol section in the old format, and the string, symbol, constant and
+  // declaration sections in the compact one
+  rows.push(Row {
+    name: "(header)",
+    count: symbols.len(),
+    old: 4 + symbols.iter().map(|s| 4 + s.len()).sum::<usize>(),
+    compact: compact - rows.iter().map(|r| r.compact).sum::<usize>(),
+  });
+
+  let old = rows.iter().map(|r| r.old).sum::<usize>();
+
+  println!("{:<12} {:>8} {:>10} {:>10}", "Instruction", "Count", "Old", "Compact");
+
+  for row in &rows {
+    println!("{:<12} {:>8} {:>10} {:>10}", row.name, row.count, row.old, row.compact);
+  }
+
+  println!("{:<12} {:>8} {:>10} {:>10}", "Total", ast.len(), old, compact);
+
+  if old > 0 {
+    println!("\nThe compact encoding is {:.1}% of the old one.", compact as f64 * 100.0 / old as f64);
+  }
+}
+
+/// How many bytes the old format took for an instruction, with its opcode.
+fn old_size(data: &AstNodeData) -> usize {
+  1 + match data {
+    AstNodeData::Pushc(value) => value_size(value),
+    AstNodeData::Setc(_, value) | AstNodeData::Const(_, value) => value_size(value) + 4,
+
+    AstNodeData::Pushv(_)
+    | AstNodeData::Popv(_)
+    | AstNodeData::Ref(_)
+    | AstNodeData::Drop(_)
+    | AstNodeData::Pick(_) => 4,
+
+    // superinstructions didn't exist, so they take what the instructions they stand for took:
+    // `pushv`, `inc` or `dec` and `popv`, and `pushv`, `pushc`, the comparison, `pushc` and `jt`
+    AstNodeData::Incv(_) | AstNodeData::Decv(_) => 4 + 1 + 1 + 4,
+    AstNodeData::Jcmp(_, _, value, label) => 4 + 1 + value_size(value) + 1 + 1 + value_size(&Value::Label(label.as_ref().into())) + 1,
+
+    AstNodeData::Label(name)
+    | AstNodeData::New(name)
+    | AstNodeData::Getf(name)
+    | AstNodeData::Setf(name) => 4 + name.len(),
+
+    AstNodeData::Struct(name, fields) => 4 + name.len() + 4 + fields.iter().map(|(f, ty)| 4 + f.len() + type_size(ty)).sum::<usize>(),
+    AstNodeData::Decl(_, ty) => 4 + type_size(ty),
+
+    _ => 0,
+  }
+}
+
+fn value_size(value: &Value) -> usize {
+  1 + match value {
+    Value::Num(_) => 8,
+    Value::Str(s) | Value::Label(s) => 4 + s.len(),
+    Value::Bool(_) => 1,
+    Value::List(list) => 4 + list.borrow().iter().map(value_size).sum::<usize>(),
+    Value::Map(map) => 4 + map.borrow().iter().map(|(k, v)| 4 + k.len() + value_size(v)).sum::<usize>(),
+    Value::Nil | Value::Ref(..) | Value::Record(_) => 0,
+  }
+}
+
+fn type_size(ty: &Type) -> usize {
+  match ty {
+    Type::Struct(name) => 1 + 4 + name.len(),
+    _ => 1,
+  }
+}
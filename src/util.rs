@@ -11,7 +11,7 @@ pub fn is_identifier(s: &str) -> bool {
 }
 
 pub fn is_label(s: &str) -> bool {
-  s.starts_with('#') && is_identifier(&s[1..])
+  s.len() > 1 && s.starts_with('#') && is_identifier(&s[1..])
 }
 
 pub fn print_error(msg: &str, code: &str, mut line: usize) {
@@ -41,9 +41,31 @@ pub fn custom_split(input: &str) -> Vec<String> {
   let mut result = Vec::new();
   let mut current_word = String::new();
   let mut in_quotes = false;
+  let mut depth = 0; // nesting level of list and map literals, which are kept verbatim in a single word
   
   for c in input.chars() {
-    if c.is_whitespace() && !in_quotes {
+    if depth > 0 {
+      current_word.push(c);
+
+      if c == '"' {
+        in_quotes = !in_quotes;
+      }
+
+      else if !in_quotes && (c == '[' || c == '{') {
+        depth += 1;
+      }
+
+      else if !in_quotes && (c == ']' || c == '}') {
+        depth -= 1;
+      }
+    }
+
+    else if (c == '[' || c == '{') && !in_quotes {
+      depth = 1;
+      current_word.push(c);
+    }
+
+    else if c.is_whitespace() && !in_quotes {
       if !current_word.is_empty() {
         result.push(current_word.clone());
         current_word.clear();
@@ -65,7 +87,7 @@ pub fn custom_split(input: &str) -> Vec<String> {
   }
   
   if !current_word.is_empty() {
-    if in_quotes {
+    if in_quotes && depth == 0 {
       result.push(format!("\"{}\"", current_word));
     }
     
@@ -76,3 +98,46 @@ pub fn custom_split(input: &str) -> Vec<String> {
   
   result
 }
+
+/// Splits the inside of a list or map literal at its top-level commas, leaving
+/// commas inside strings and nested literals alone.
+pub fn split_literal_items(input: &str) -> Vec<&str> {
+  let mut result = Vec::new();
+  let mut start = 0;
+
+  while let Some(i) = find_top_level(&input[start..], ',') {
+    result.push(input[start..start + i].trim());
+    start += i + 1;
+  }
+
+  let last = input[start..].trim();
+
+  if !last.is_empty() || !result.is_empty() {
+    result.push(last);
+  }
+
+  result
+}
+
+/// Splits a `key: value` map literal entry at its first top-level colon.
+pub fn split_map_entry(input: &str) -> Option<(&str, &str)> {
+  let i = find_top_level(input, ':')?;
+  Some((input[..i].trim(), input[i + 1..].trim()))
+}
+
+fn find_top_level(input: &str, target: char) -> Option<usize> {
+  let mut in_quotes = false;
+  let mut depth = 0;
+
+  for (i, c) in input.char_indices() {
+    match c {
+      '"' => in_quotes = !in_quotes,
+      '[' | '{' if !in_quotes => depth += 1,
+      ']' | '}' if !in_quotes => depth -= 1,
+      c if c == target && !in_quotes && depth == 0 => return Some(i),
+      _ => {}
+    }
+  }
+
+  None
+}
@@ -0,0 +1,59 @@
+// each test crate uses only some of these
+#![allow(dead_code)]
+
+use std::{fs, path::{Path, PathBuf}, process::{Command, Stdio}};
+
+/// What a run of Machina printed, and the code it exited with.
+#[derive(Debug, PartialEq)]
+pub struct Output {
+  pub stdout: String,
+  pub stderr: String,
+  pub code: Option<i32>,
+}
+
+/// Writes `source` to a directory of its own, named after `name`.
+pub fn write(name: &str, source: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("machina-{}-{}", std::process::id(), name));
+  fs::create_dir_all(&dir).unwrap();
+
+  let path = dir.join(format!("{name}.asm"));
+  fs::write(&path, source).unwrap();
+  path
+}
+
+/// Runs Machina with `args`, followed by `path`.
+pub fn machina(args: &[&str], path: &PathBuf) -> Output {
+  let output = Command::new(env!("CARGO_BIN_EXE_machina"))
+    .args(args)
+    .arg(path)
+    .stdin(Stdio::null())
+    .output()
+    .unwrap();
+
+  Output {
+    stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+    stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    code: output.status.code(),
+  }
+}
+
+/// Assembles the program at `path` with `flags`, failing the test if it can't be.
+pub fn assemble(flags: &[&str], path: &PathBuf) {
+  let assembled = machina(&[&["assemble"], flags].concat(), path);
+  assert_eq!(assembled.code, Some(0), "couldn't assemble {} {flags:?}: {}", path.display(), assembled.stderr);
+}
+
+/// Assembles `source` with the `assemble` flags, then runs it with the `run` ones.
+pub fn run(name: &str, source: &str, assemble_flags: &[&str], run: &[&str]) -> Output {
+  let path = write(name, source);
+  assemble(assemble_flags, &path);
+
+  let output = machina(&[&["run"], run].concat(), &path.with_extension("mch"));
+  remove(&path);
+  output
+}
+
+/// Removes the directory `write` made.
+pub fn remove(path: &Path) {
+  fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
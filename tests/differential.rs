@@ -0,0 +1,62 @@
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{assemble, machina, remove, write};
+
+const FLAGS: [&[&str]; 4] = [&[], &["--undefined-as-nil"], &["--strict"], &["--stream"]];
+
+/// Assembles every program in `dir` with and without `-O`, and checks that with every flag:
+/// - the stack-based interpreter and `--registers` print the same, fail with the same errors and exit with the same code;
+/// - the program prints the same and exits with the same code with and without `-O` (errors may point to another instruction).
+fn differential(dir: &str) {
+  let mut paths: Vec<_> = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join(dir))
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .filter(|path| path.extension().is_some_and(|e| e == "asm"))
+    .collect();
+  paths.sort();
+
+  // both directories can have a program with the same name
+  let prefix = Path::new(dir).file_name().unwrap().to_str().unwrap();
+
+  for path in paths {
+    let name = format!("{}-{}", prefix, path.file_stem().unwrap().to_str().unwrap());
+    let source = fs::read_to_string(&path).unwrap();
+
+    let plain = write(&name, &source);
+    let optimized = write(&format!("{name}-O"), &source);
+
+    assemble(&[], &plain);
+    assemble(&["-O"], &optimized);
+
+    for flags in FLAGS {
+      let mut outputs = vec![];
+
+      for program in [&plain, &optimized] {
+        let program = program.with_extension("mch");
+        let stack = machina(&[&["run"], flags].concat(), &program);
+        let registers = machina(&[&["run"], flags, &["--registers"]].concat(), &program);
+
+        assert_eq!(stack, registers, "{} {flags:?}: the stack-based interpreter and --registers differ", program.display());
+        outputs.push(stack);
+      }
+
+      let [plain, optimized] = &outputs[..] else { unreachable!() };
+      assert_eq!((&plain.stdout, plain.code), (&optimized.stdout, optimized.code), "{name} {flags:?}: outputs differ with and without -O");
+    }
+
+    remove(&plain);
+    remove(&optimized);
+  }
+}
+
+#[test]
+fn programs_run_the_same_everywhere() {
+  differential("tests/programs");
+}
+
+#[test]
+fn benchmarks_run_the_same_everywhere() {
+  differential("benches");
+}
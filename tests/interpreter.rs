@@ -0,0 +1,198 @@
+mod common;
+
+use std::fs;
+
+use common::{machina, remove, run, write};
+
+#[test]
+fn pushv_of_undefined_variable_stops_the_program() {
+  let output = run("pushv_undefined", "pushv nope\npushc \"after\"\nprintln\n", &[], &[]);
+
+  assert_eq!(output.code, Some(1));
+  assert_eq!(output.stdout, "");
+  assert!(output.stderr.contains("In 'pushv' instruction: Variable 'nope' doesn't exist"), "{}", output.stderr);
+}
+
+#[test]
+fn pushv_of_unassigned_variable_stops_the_program() {
+  let output = run("pushv_unassigned", "decl n num\npushv n\npushc \"after\"\nprintln\n", &[], &[]);
+
+  assert_eq!(output.code, Some(1));
+  assert_eq!(output.stdout, "");
+  assert!(output.stderr.contains("Variable 'n' was declared but never assigned"), "{}", output.stderr);
+}
+
+#[test]
+fn pushv_of_undefined_variable_is_nil_with_undefined_as_nil() {
+  let output = run("pushv_nil", "pushv nope\nisnil\nprintln\n", &[], &["--undefined-as-nil"]);
+
+  assert_eq!(output.code, Some(0));
+  assert_eq!(output.stdout, "true\n");
+}
+
+#[test]
+fn substr_with_an_oversized_length_is_out_of_bounds() {
+  let output = run("substr_oversized", "pushc 1e300\npushc 1\npushc \"abc\"\nsubstr\nprintln\n", &[], &[]);
+
+  assert_eq!(output.code, Some(1), "{}", output.stderr);
+  assert!(output.stderr.contains("In 'substr' instruction: Range 1.."), "{}", output.stderr);
+  assert!(output.stderr.contains("is out of bounds for a string of length 3"), "{}", output.stderr);
+}
+
+#[test]
+fn listslice_with_an_oversized_length_is_out_of_bounds() {
+  let output = run("listslice_oversized", "pushc 1e300\npushc 1\npushc [1, 2, 3]\nlistslice\nprintln\n", &[], &[]);
+
+  assert_eq!(output.code, Some(1), "{}", output.stderr);
+  assert!(output.stderr.contains("In 'listslice' instruction: Range 1.."), "{}", output.stderr);
+  assert!(output.stderr.contains("is out of bounds for a list of length 3"), "{}", output.stderr);
+}
+
+#[test]
+fn listslice_copies_the_range() {
+  let output = run("listslice", "pushc 2\npushc 1\npushc [1, 2, 3, 4]\nlistslice\nprintln\n", &[], &[]);
+
+  assert_eq!(output.code, Some(0), "{}", output.stderr);
+  assert_eq!(output.stdout, "[2, 3]\n");
+}
+
+const DECLARATIONS: &str = r#"
+pushc 2
+pushc 1
+new Point
+println
+pushc #forward
+jmp
+pushc "skipped"
+println
+#forward
+setc i 0
+#loop
+pushv i
+inc
+popv i
+pushv i
+pushc 3
+cmpg
+pushc #loop
+jt
+pushv i
+println
+.struct Point x:num y:num
+"#;
+
+#[test]
+fn streaming_finds_labels_and_structs() {
+  let decoded = run("declarations_decoded", DECLARATIONS, &[], &[]);
+  let streamed = run("declarations_streamed", DECLARATIONS, &[], &["--stream"]);
+
+  assert_eq!(decoded.code, Some(0), "{}", decoded.stderr);
+  assert_eq!(decoded.stdout, streamed.stdout);
+  assert_eq!(decoded.code, streamed.code);
+}
+
+#[test]
+fn streaming_reports_structs_declared_twice() {
+  let output = run("struct_twice", ".struct P x:num\n.struct P y:num\n", &[], &["--stream"]);
+
+  assert_eq!(output.code, Some(1));
+  assert!(output.stderr.contains("Struct 'P' is declared more than once"), "{}", output.stderr);
+}
+
+#[test]
+fn streaming_starts_without_decoding_every_instruction() {
+  let path = write("stream_lazily", "pushc \"started\"\nprintln\n#end\n");
+  assert_eq!(machina(&["assemble"], &path).code, Some(0));
+
+  // an instruction that can't be decoded at the end, which only stops the program once it's reached
+  let bytecode = path.with_extension("mch");
+  let mut bytes = fs::read(&bytecode).unwrap();
+  bytes.push(120);
+  fs::write(&bytecode, bytes).unwrap();
+
+  let streamed = machina(&["run", "--stream"], &bytecode);
+  let decoded = machina(&["run"], &bytecode);
+  remove(&path);
+
+  assert_eq!(streamed.stdout, "started\n");
+  assert_eq!(streamed.code, Some(1));
+  assert!(streamed.stderr.contains("Invalid instruction code: 120"), "{}", streamed.stderr);
+
+  assert_eq!(decoded.stdout, "");
+  assert_eq!(decoded.code, Some(1));
+}
+
+#[cfg(feature = "alloc-stats")]
+#[test]
+fn alloc_stats_counts_allocations() {
+  let output = run("alloc_stats", "pushc [1, 2]\nprintln\n", &[], &["--alloc-stats"]);
+
+  assert_eq!(output.code, Some(0), "{}", output.stderr);
+  assert!(output.stderr.starts_with("Allocations: "), "{}", output.stderr);
+}
+
+#[cfg(not(feature = "alloc-stats"))]
+#[test]
+fn alloc_stats_needs_the_feature() {
+  let output = run("alloc_stats", "pushc [1, 2]\nprintln\n", &[], &["--alloc-stats"]);
+
+  assert_eq!(output.code, Some(1));
+  assert_eq!(output.stdout, "");
+  assert!(output.stderr.contains("'alloc-stats' feature"), "{}", output.stderr);
+}
+
+#[test]
+fn record_type_accepts_records_of_any_struct() {
+  let source = r#"
+.struct Point x:num
+.struct Tag name:str
+.struct Holder item:record
+decl r record
+pushc 1
+new Point
+popv r
+pushc "t"
+new Tag
+dup
+popv r
+new Holder
+getf item
+getf name
+println
+pushc 1
+popv r
+"#;
+  let output = run("record_type", source, &[], &[]);
+
+  assert_eq!(output.stdout, "t\n");
+  assert_eq!(output.code, Some(1));
+  assert!(output.stderr.contains("since it was declared as record"), "{}", output.stderr);
+}
+
+#[test]
+fn decl_of_an_undeclared_struct_fails_before_running() {
+  let source = "pushc \"ran\"\nprintln\ndecl p Nope\n";
+
+  for flags in [&[][..], &["--stream"]] {
+    let output = run("decl_undeclared_struct", source, &[], flags);
+
+    assert_eq!(output.stdout, "", "{:?}", flags);
+    assert_eq!(output.code, Some(1));
+    assert!(output.stderr.contains("Type 'Nope' of variable 'p' doesn't exist"), "{}", output.stderr);
+  }
+}
+
+#[test]
+fn tolabel_fails_on_strings_that_arent_labels() {
+  let mut source = String::new();
+
+  for s in ["#", "#1", "1a", "a b", "##a"] {
+    source += &format!("pushc \"{}\"\ntolabel\nprintln\npop\n", s);
+  }
+
+  source += "pushc \"#a\"\ntolabel\nprintln\nprintln\npushc \"a\"\ntolabel\nprintln\nprintln\n";
+  let output = run("tolabel_failures", &source, &[], &[]);
+
+  assert_eq!(output.stdout, "false\n".repeat(5) + "true\n#a\ntrue\n#a\n", "{}", output.stderr);
+  assert_eq!(output.code, Some(0));
+}
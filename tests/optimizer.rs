@@ -0,0 +1,224 @@
+mod common;
+
+use std::fs;
+
+use common::{machina, remove, run, write};
+
+/// Runs `source` with and without `-O`, checking that both print the same and stop the same way.
+/// Errors may point to another instruction once optimized, so only whether there was one is compared.
+fn same_with_and_without_optimizations(name: &str, source: &str) {
+  let plain = run(&format!("{name}_plain"), source, &[], &[]);
+  let optimized = run(&format!("{name}_optimized"), source, &["-O"], &[]);
+
+  assert_eq!(plain.stdout, optimized.stdout, "{name}");
+  assert_eq!(plain.code, optimized.code, "{name}: {}", optimized.stderr);
+  assert_eq!(plain.stderr.is_empty(), optimized.stderr.is_empty(), "{name}: {}", optimized.stderr);
+}
+
+const FOLDING: &str = r#"
+pushc 10
+pushc 20
+add
+println
+pushc 2
+pushc 3
+pow
+pushc 4
+mul
+println
+pushc "a"
+pushc "b"
+cmpg
+println
+pushc "x"
+pushc "y"
+add
+println
+pushc 2.5
+round
+neg
+println
+pushc 16
+sqrt
+abs
+floor
+ceil
+println
+pushc false
+lnot
+println
+"#;
+
+const CONSTANT_JUMPS: &str = r#"
+pushc true
+pushc #a
+jt
+pushc "not printed"
+println
+#a
+pushc false
+pushc #b
+jt
+pushc "printed"
+println
+#b
+pushc false
+pushc #c
+jf
+pushc "not printed"
+println
+#c
+pushc true
+pushc #d
+jf
+pushc "printed"
+println
+#d
+"#;
+
+const UNREACHABLE: &str = r#"
+setc i 0
+#loop
+pushv i
+println
+pushv i
+inc
+popv i
+pushc #check
+jmp
+pushc "dead"
+println
+#unused
+pushc "dead too"
+println
+#check
+pushv i
+pushc 3
+cmpg
+pushc #loop
+jt
+pushc #end
+jmp
+pushc "dead"
+println
+#end
+"#;
+
+const LABELS_IN_CONSTANTS: &str = r##"
+setc targets [#a, #b]
+pushc 1
+pushv targets
+listget
+jmp
+#a
+pushc "a"
+println
+#b
+pushc "b"
+println
+pushc "#c"
+tolabel
+pop
+jmp
+pushc "dead"
+println
+#c
+pushc "c"
+println
+"##;
+
+const FAILING_FOLD: &str = r#"
+pushc "before"
+println
+pushc "a"
+pushc 2
+mul
+println
+"#;
+
+const FAILING_UNARY_FOLD: &str = r#"
+pushc "before"
+println
+pushc -4
+sqrt
+println
+"#;
+
+#[test]
+fn folding_prints_the_same() {
+  same_with_and_without_optimizations("folding", FOLDING);
+}
+
+#[test]
+fn constant_jumps_print_the_same() {
+  same_with_and_without_optimizations("constant_jumps", CONSTANT_JUMPS);
+}
+
+#[test]
+fn removing_unreachable_code_prints_the_same() {
+  same_with_and_without_optimizations("unreachable", UNREACHABLE);
+}
+
+#[test]
+fn labels_in_constants_print_the_same() {
+  same_with_and_without_optimizations("labels_in_constants", LABELS_IN_CONSTANTS);
+}
+
+#[test]
+fn failing_folds_still_fail() {
+  same_with_and_without_optimizations("failing_fold", FAILING_FOLD);
+}
+
+#[test]
+fn failing_unary_folds_still_fail() {
+  same_with_and_without_optimizations("failing_unary_fold", FAILING_UNARY_FOLD);
+}
+
+#[test]
+fn emitted_asm_assembles_and_prints_the_same() {
+  for (name, source) in [("emit_folding", FOLDING), ("emit_jumps", CONSTANT_JUMPS), ("emit_unreachable", UNREACHABLE), ("emit_labels", LABELS_IN_CONSTANTS)] {
+    let path = write(name, source);
+    let emitted = machina(&["assemble", "--emit-optimized-asm"], &path);
+    assert_eq!(emitted.code, Some(0), "{name}: {}", emitted.stderr);
+
+    let reassembled = path.with_file_name(format!("{name}_emitted.asm"));
+    fs::write(&reassembled, &emitted.stdout).unwrap();
+
+    let plain = run(&format!("{name}_plain"), source, &[], &[]);
+    assert_eq!(machina(&["assemble"], &reassembled).code, Some(0), "{name}");
+    assert_eq!(machina(&["run"], &reassembled.with_extension("mch")), plain, "{name}");
+
+    remove(&path);
+  }
+}
+
+#[test]
+fn keeping_popped_values_prints_the_same() {
+  same_with_and_without_optimizations("popv_pushv", "pushc 5\npopv x\npushv x\nprintln\npushv x\nprintln\n");
+  same_with_and_without_optimizations("popv_pushv_error", "decl n num\npushc \"s\"\npopv n\npushv n\nprintln\n");
+}
+
+#[test]
+fn inc_and_dec_print_the_same() {
+  same_with_and_without_optimizations("inc_dec", "pushc 5\npushc 1\nadd\nprintln\npushc 5\npushc -1\nadd\nprintln\n");
+  same_with_and_without_optimizations("inc_error", "pushc \"s\"\npushc 1\nadd\nprintln\n");
+  same_with_and_without_optimizations("dec_error", "pushc true\npushc -1\nadd\nprintln\n");
+}
+
+#[test]
+fn negated_comparisons_print_the_same() {
+  same_with_and_without_optimizations("negated", "pushc 1\npushc 2\ncmpe\nlnot\nprintln\npushc \"a\"\npushc \"a\"\ncmpne\nlnot\nprintln\n");
+  same_with_and_without_optimizations("negated_error", "pushc 1\npushc \"a\"\ncmpe\nlnot\nprintln\n");
+}
+
+#[test]
+fn jumps_after_lnot_print_the_same() {
+  same_with_and_without_optimizations("lnot_jt", "setc b false\npushv b\nlnot\npushc #l\njt\npushc \"skipped\"\nprintln\n#l\npushc \"end\"\nprintln\n");
+  same_with_and_without_optimizations("lnot_jf", "setc b false\npushv b\nlnot\npushc #l\njf\npushc \"printed\"\nprintln\n#l\npushc \"end\"\nprintln\n");
+  same_with_and_without_optimizations("lnot_error", "setc b 1\npushv b\nlnot\npushc #l\njt\n#l\n");
+}
+
+#[test]
+fn removing_popped_constants_prints_the_same() {
+  same_with_and_without_optimizations("pushc_pop", "pushc \"unused\"\npop\npushc \"used\"\nprintln\n");
+}
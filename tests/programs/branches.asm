@@ -0,0 +1,28 @@
+setc a 3
+setc b 4
+pushv a
+pushv b
+cmpl
+jf #x
+pushc "less"
+println
+#x
+pushv a
+pushv b
+cmpl
+pushc #y
+jt
+pushc "no"
+println
+#y
+pushc true
+pushc #z
+jf
+pushc "z"
+println
+#z
+pushv a
+pushv b
+cmpe
+pushc #missing
+jt
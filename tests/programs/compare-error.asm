@@ -0,0 +1,7 @@
+setc q 1
+pushv q
+pushc "a"
+cmpg
+pushc #e
+jt
+#e
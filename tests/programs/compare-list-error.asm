@@ -0,0 +1,6 @@
+setc s [1]
+pushv s
+pushc 1
+cmpl
+jt #x
+#x
@@ -0,0 +1,3 @@
+const k 1
+pushc 2
+popv k
@@ -0,0 +1,27 @@
+pushc "#t"
+tolabel
+pop
+setc l #t
+pushv l
+jmp
+pushc "skipped"
+println
+#t
+pushc "t"
+println
+pushc "a b c"
+pushc " "
+swap
+split
+println
+println
+println
+println
+pushc 1
+pushv l
+pushc 2
+pushc 3
+add
+println
+pop
+println
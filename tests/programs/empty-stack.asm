@@ -0,0 +1,2 @@
+pushc 1
+add
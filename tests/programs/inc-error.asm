@@ -0,0 +1,2 @@
+pushc "s"
+inc
@@ -0,0 +1,4 @@
+setc s "str"
+pushv s
+inc
+println
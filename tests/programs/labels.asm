@@ -0,0 +1,22 @@
+setc a 1
+pushv a
+pushc 2
+add
+#l
+popv b
+pushv b
+println
+pushc 5
+pushc 7
+#m
+sub
+println
+pushc 2
+pushc 3
+mul
+dup
+popv c
+pushv c
+add
+println
+inc
@@ -0,0 +1,26 @@
+setc i 0
+#loop
+pushv i
+pushv i
+pushc 1
+add
+popv i
+pushv i
+pushc 5
+cmpg
+jt #loop
+pushc ","
+pushc 6
+swap
+pop
+pushc "-"
+join
+println
+pushc [1, 2, 3]
+listunpack
+pushc 1
+add
+println
+println
+println
+println
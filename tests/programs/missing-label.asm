@@ -0,0 +1,2 @@
+pushc #nowhere
+jmp
@@ -0,0 +1,28 @@
+setc a "x"
+setc b "y"
+pushv a
+pushv b
+add
+println
+setc l [1, 2]
+pushv l
+pushc [1, 2]
+cmpe
+println
+pushv a
+pushc "x"
+cmpe
+jt #eq
+pushc "no"
+println
+#eq
+setc n 2.5
+pushv n
+inc
+popv n
+pushv n
+println
+pushv a
+pushc 1
+add
+println
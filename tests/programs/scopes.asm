@@ -0,0 +1,25 @@
+setc x 1
+save
+pushv x
+pushc 1
+add
+popv x
+pushv x
+println
+ret
+pushv x
+println
+pushv x
+save
+popv x
+pushv x
+println
+ret
+pushv x
+ref x
+store
+pushv x
+println
+pushv nope
+pushc 1
+add
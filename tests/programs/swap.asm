@@ -0,0 +1,16 @@
+setc x 1
+setc y 2
+pushv x
+pushv y
+popv x
+popv y
+pushv x
+println
+pushv y
+println
+pushv x
+pushc 10
+popv x
+pushv x
+add
+println
@@ -0,0 +1,3 @@
+decl n num
+pushc "x"
+popv n
@@ -0,0 +1,4 @@
+pushc 1
+pushv u
+pushv w
+add